@@ -0,0 +1,237 @@
+#[cfg(feature = "gif")]
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::DynamicImage;
+#[cfg(feature = "gif")]
+use image::{Delay, Frame};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use strum_macros::{Display, EnumIter, EnumString};
+use thiserror::Error;
+
+use crate::export::{export_image, ExportError, ExportOptions};
+use crate::quantize::{quantize, Dither};
+use crate::DrawResponse;
+
+/// Draws the frame at a time from 0 to 1.
+pub type FrameFn = Box<dyn Fn(f64) -> DrawResponse + Send + Sync>;
+
+pub struct Animation {
+    pub frame: FrameFn,
+    /// Whether the frame at time 1 is the same as the frame at time 0.
+    /// Animations that are not periodic are played forwards and then
+    /// backwards when they loop.
+    pub periodic: bool,
+}
+
+#[derive(Error, Debug)]
+pub enum MorphError {
+    #[error("both designs must have the same {0}")]
+    Mismatch(&'static str),
+}
+
+/// Interpolates linearly from `a` at time 0 to `b` at time 1.
+pub fn mix<T>(a: T, b: T, t: f64) -> T
+where
+    T: std::ops::Add<Output = T> + std::ops::Mul<f64, Output = T>,
+{
+    a * (1. - t) + b * t
+}
+
+/// Interpolates between two angles, in radians, the short way around.
+pub fn mix_angle(a: f64, b: f64, t: f64) -> f64 {
+    let d =
+        (b - a + std::f64::consts::PI).rem_euclid(2. * std::f64::consts::PI) - std::f64::consts::PI;
+    a + t * d
+}
+
+/// Takes `a` for the first half of a morph and `b` for the second, for
+/// choices that cannot be blended.
+pub fn pick<T>(a: T, b: T, t: f64) -> T {
+    if t < 0.5 {
+        a
+    } else {
+        b
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum AnimationFormat {
+    /// An animated PNG, `name.png`.
+    #[default]
+    Apng,
+    /// An animated GIF, `name.gif`.
+    Gif,
+    /// One PNG for each frame, `name_<frame>.png`.
+    PngSequence,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnimationOptions {
+    #[serde(default = "default_frames")]
+    pub frames: u32,
+    /// The time each frame is shown, in milliseconds.
+    #[serde(default = "default_frame_delay")]
+    pub frame_delay: u16,
+    #[serde(default)]
+    pub format: AnimationFormat,
+    /// Whether the animation repeats.  The last frame then leads back into
+    /// the first.
+    #[serde(default = "default_looped")]
+    pub looped: bool,
+    #[serde(default)]
+    pub dither: Dither,
+}
+
+fn default_frames() -> u32 {
+    48
+}
+
+fn default_frame_delay() -> u16 {
+    40
+}
+
+fn default_looped() -> bool {
+    true
+}
+
+impl Default for AnimationOptions {
+    fn default() -> Self {
+        Self {
+            frames: default_frames(),
+            frame_delay: default_frame_delay(),
+            format: AnimationFormat::default(),
+            looped: default_looped(),
+            dither: Dither::default(),
+        }
+    }
+}
+
+/// The times of the frames.  A loop never repeats its first frame at the
+/// end, so that it plays without a stutter.
+pub fn frame_times(frames: u32, periodic: bool, looped: bool) -> Vec<f64> {
+    let n = frames.max(1) as f64;
+    (0..frames.max(1))
+        .map(|i| {
+            let i = i as f64;
+            match (periodic, looped) {
+                (true, _) => i / n,
+                (false, true) => 1. - (1. - 2. * i / n).abs(),
+                (false, false) if n > 1. => i / (n - 1.),
+                (false, false) => 0.,
+            }
+        })
+        .collect()
+}
+
+fn quantized_frames<'a>(
+    anim: &'a Animation,
+    opts: &'a AnimationOptions,
+) -> impl Iterator<Item = DynamicImage> + 'a {
+    frame_times(opts.frames, anim.periodic, opts.looped)
+        .into_iter()
+        .map(move |t| {
+            let frame = (anim.frame)(t);
+            quantize(&frame.im, opts.dither, frame.is_periodic())
+        })
+}
+
+fn write_apng<W: Write>(
+    anim: &Animation,
+    opts: &AnimationOptions,
+    w: W,
+) -> Result<(), ExportError> {
+    let mut frames = quantized_frames(anim, opts);
+    let Some(first) = frames.next() else {
+        return Ok(());
+    };
+    let alpha = first.color().has_alpha();
+    let mut enc = png::Encoder::new(w, first.width(), first.height());
+    enc.set_color(if alpha {
+        png::ColorType::Rgba
+    } else {
+        png::ColorType::Rgb
+    });
+    enc.set_depth(png::BitDepth::Eight);
+    enc.set_animated(opts.frames.max(1), if opts.looped { 0 } else { 1 })?;
+    enc.set_frame_delay(opts.frame_delay, 1000)?;
+    enc.set_blend_op(png::BlendOp::Source)?;
+    let mut writer = enc.write_header()?;
+    for im in std::iter::once(first).chain(frames) {
+        if alpha {
+            writer.write_image_data(im.to_rgba8().as_raw())?;
+        } else {
+            writer.write_image_data(im.to_rgb8().as_raw())?;
+        }
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(feature = "gif")]
+fn write_gif<W: Write>(anim: &Animation, opts: &AnimationOptions, w: W) -> Result<(), ExportError> {
+    let mut enc = GifEncoder::new_with_speed(w, 10);
+    enc.set_repeat(if opts.looped {
+        Repeat::Infinite
+    } else {
+        Repeat::Finite(0)
+    })?;
+    let delay = Delay::from_numer_denom_ms(opts.frame_delay as u32, 1);
+    for im in quantized_frames(anim, opts) {
+        enc.encode_frame(Frame::from_parts(im.to_rgba8(), 0, 0, delay))?;
+    }
+    Ok(())
+}
+
+/// Renders the frames of an animation one at a time and writes them to
+/// `dir` in the requested format, with 8 bits per channel.
+pub fn export_animation(
+    anim: &Animation,
+    opts: &AnimationOptions,
+    dir: &Path,
+    name: &str,
+) -> Result<(), ExportError> {
+    fs::create_dir_all(dir)?;
+    match opts.format {
+        AnimationFormat::Apng => {
+            let mut w = BufWriter::new(File::create(dir.join(format!("{}.png", name)))?);
+            write_apng(anim, opts, &mut w)?;
+            w.flush()?;
+        }
+        #[cfg(feature = "gif")]
+        AnimationFormat::Gif => {
+            let mut w = BufWriter::new(File::create(dir.join(format!("{}.gif", name)))?);
+            write_gif(anim, opts, &mut w)?;
+            w.flush()?;
+        }
+        #[cfg(not(feature = "gif"))]
+        AnimationFormat::Gif => return Err(ExportError::AnimationDisabled(opts.format)),
+        AnimationFormat::PngSequence => {
+            let export = ExportOptions {
+                dither: opts.dither,
+                ..ExportOptions::default()
+            };
+            let times = frame_times(opts.frames, anim.periodic, opts.looped);
+            for (i, t) in times.into_iter().enumerate() {
+                let frame = (anim.frame)(t);
+                let path = dir.join(format!("{}_{:04}.png", name, i));
+                let mut w = BufWriter::new(File::create(path)?);
+                export_image(&frame.im, frame.is_periodic(), &export, &mut w)?;
+                w.flush()?;
+            }
+        }
+    }
+    Ok(())
+}
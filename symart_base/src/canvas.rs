@@ -82,6 +82,9 @@ impl<T: Copy> WrapCanvas<T> {
 }
 
 impl<T> WrapCanvas<MaybeUninit<T>> {
+    /// # Safety
+    ///
+    /// Every element of the canvas must have been initialized.
     pub unsafe fn assume_init(self) -> WrapCanvas<T> {
         WrapCanvas {
             array: self.array.assume_init(),
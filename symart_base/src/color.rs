@@ -0,0 +1,435 @@
+use image::Rgb;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use strum_macros::{Display, EnumIter, EnumString};
+
+use crate::random::{self, Slice};
+use crate::rng::sample;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseColorError(String);
+
+impl fmt::Display for ParseColorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid color: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseColorError {}
+
+pub fn parse_hex(s: &str) -> Result<Rgb<u8>, ParseColorError> {
+    let err = || ParseColorError(s.to_string());
+    let hex = s.strip_prefix('#').ok_or_else(err)?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(err());
+    }
+    let mut rgb = [0; 3];
+    for (i, c) in rgb.iter_mut().enumerate() {
+        *c = u8::from_str_radix(&hex[(2 * i)..(2 * i + 2)], 16).map_err(|_| err())?;
+    }
+    Ok(Rgb(rgb))
+}
+
+/// Converts a color with channels in `[0, 1]` to 8 bits per channel.
+pub fn to_rgb8(c: [f64; 3]) -> Rgb<u8> {
+    Rgb(c.map(|v| (255.99 * v.clamp(0., 1.)) as u8))
+}
+
+pub fn to_hex(c: Rgb<u8>) -> String {
+    format!("#{:02x}{:02x}{:02x}", c[0], c[1], c[2])
+}
+
+/// The color that layers are composited onto.  `Transparent` produces an
+/// image with an alpha channel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Background {
+    Color(Rgb<u8>),
+    Transparent,
+}
+
+impl Background {
+    /// The background a fraction `t` of the way to `other`.  A transparent
+    /// background cannot be blended, so it switches halfway.
+    pub fn mix(self, other: Background, t: f64) -> Background {
+        match (self, other) {
+            (Background::Color(a), Background::Color(b)) => Background::Color(lerp(a, b, t)),
+            _ if t < 0.5 => self,
+            _ => other,
+        }
+    }
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Color(Rgb([0, 0, 0]))
+    }
+}
+
+impl FromStr for Background {
+    type Err = ParseColorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("transparent") {
+            Ok(Background::Transparent)
+        } else {
+            parse_hex(s).map(Background::Color)
+        }
+    }
+}
+
+impl TryFrom<String> for Background {
+    type Error = ParseColorError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Background {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Background::Color(c) => f.write_str(&to_hex(*c)),
+            Background::Transparent => f.write_str("transparent"),
+        }
+    }
+}
+
+impl From<Background> for String {
+    fn from(b: Background) -> String {
+        b.to_string()
+    }
+}
+
+/// A color written as `#rrggbb`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct HexColor(pub Rgb<u8>);
+
+impl TryFrom<String> for HexColor {
+    type Error = ParseColorError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        parse_hex(&s).map(HexColor)
+    }
+}
+
+impl From<HexColor> for String {
+    fn from(c: HexColor) -> String {
+        to_hex(c.0)
+    }
+}
+
+pub(crate) fn lerp(c1: Rgb<u8>, c2: Rgb<u8>, t: f64) -> Rgb<u8> {
+    let mut out = [0; 3];
+    for (i, o) in out.iter_mut().enumerate() {
+        *o = ((c1[i] as f64) * (1. - t) + (c2[i] as f64) * t + 0.5) as u8;
+    }
+    Rgb(out)
+}
+
+/// Evenly spaced color stops, interpolated linearly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gradient {
+    pub colors: Vec<Rgb<u8>>,
+}
+
+impl Gradient {
+    pub fn new(colors: Vec<Rgb<u8>>) -> Self {
+        assert!(!colors.is_empty());
+        Self { colors }
+    }
+
+    /// Returns the color at position `t`, which is clamped to `[0, 1]`.
+    pub fn at(&self, t: f64) -> Rgb<u8> {
+        let n = self.colors.len() - 1;
+        let s = t.clamp(0., 1.) * (n as f64);
+        let i = (s as usize).min(n.saturating_sub(1));
+        if n == 0 {
+            self.colors[0]
+        } else {
+            lerp(self.colors[i], self.colors[i + 1], s - (i as f64))
+        }
+    }
+
+    /// Like `at`, but with channels scaled to `[0, 1]` and no rounding.
+    pub fn at_f64(&self, t: f64) -> [f64; 3] {
+        let n = self.colors.len() - 1;
+        let s = t.clamp(0., 1.) * (n as f64);
+        let i = (s as usize).min(n.saturating_sub(1));
+        let f = s - (i as f64);
+        let c1 = self.colors[i];
+        let c2 = self.colors[(i + 1).min(n)];
+        let mut out = [0.; 3];
+        for (j, o) in out.iter_mut().enumerate() {
+            *o = ((c1[j] as f64) * (1. - f) + (c2[j] as f64) * f) / 255.;
+        }
+        out
+    }
+}
+
+/// A user supplied list of colors.  An empty palette means that colors are
+/// chosen with `random::Color`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Palette(pub Vec<HexColor>);
+
+impl Palette {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn random_color(&self) -> Rgb<u8> {
+        if self.is_empty() {
+            Rgb(sample(random::Color))
+        } else {
+            sample(Slice { slice: &self.0 }).0
+        }
+    }
+
+    /// A gradient between two colors drawn from the palette.
+    pub fn random_gradient(&self) -> Gradient {
+        Gradient::new(vec![self.random_color(), self.random_color()])
+    }
+}
+
+type Poly = [[f64; 3]; 7];
+
+// Degree six polynomial fits to the matplotlib colormaps.
+const VIRIDIS: Poly = [
+    [
+        0.277_727_327_223_417_7,
+        0.005_407_344_544_966_578,
+        0.334_099_805_335_306_1,
+    ],
+    [
+        0.105_093_043_108_577_4,
+        1.404_613_529_898_575,
+        1.384_590_162_594_685,
+    ],
+    [
+        -0.330_861_828_725_556_3,
+        0.214_847_559_468_213,
+        0.095_095_163_028_236_59,
+    ],
+    [
+        -4.634_230_498_983_486,
+        -5.799_100_973_351_585,
+        -19.332_440_956_279_87,
+    ],
+    [
+        6.228_269_936_347_081,
+        14.179_933_366_805_09,
+        56.690_552_600_681_05,
+    ],
+    [
+        4.776_384_997_670_288,
+        -13.745_145_377_746_01,
+        -65.353_032_633_372_34,
+    ],
+    [
+        -5.435_455_855_934_631,
+        4.645_852_612_178_535,
+        26.312_435_249_583_2,
+    ],
+];
+
+const MAGMA: Poly = [
+    [
+        -0.002_136_485_053_939_582,
+        -0.000_749_655_052_795_221,
+        -0.005_386_127_855_323_933,
+    ],
+    [
+        0.251_660_540_737_164_2,
+        0.677_523_243_683_766_8,
+        2.494_026_599_312_351,
+    ],
+    [
+        8.353_717_279_216_625,
+        -3.577_719_514_958_484,
+        0.314_467_903_013_257_3,
+    ],
+    [
+        -27.668_733_085_768_66,
+        14.264_730_780_965_33,
+        -13.649_213_188_139_22,
+    ],
+    [
+        52.176_139_812_340_68,
+        -27.943_606_071_683_51,
+        12.944_169_442_383_94,
+    ],
+    [
+        -50.768_525_364_735_88,
+        29.046_582_821_272_91,
+        4.234_152_993_845_98,
+    ],
+    [
+        18.655_705_065_918_83,
+        -11.489_773_519_977_11,
+        -5.601_961_508_734_096,
+    ],
+];
+
+const INFERNO: Poly = [
+    [
+        0.000_218_940_369_119_226_5,
+        0.001_651_004_631_001_012,
+        -0.019_480_898_437_091_84,
+    ],
+    [
+        0.106_513_419_485_611_6,
+        0.563_956_436_788_409_1,
+        3.932_712_388_889_277,
+    ],
+    [
+        11.602_493_082_471_87,
+        -3.972_853_965_665_698,
+        -15.942_394_106_291_4,
+    ],
+    [
+        -41.703_996_131_394_59,
+        17.436_398_882_053_13,
+        44.354_145_198_728_13,
+    ],
+    [
+        77.162_935_699_427,
+        -33.402_358_942_100_92,
+        -81.807_309_257_389_93,
+    ],
+    [
+        -71.319_428_244_992_14,
+        32.626_064_263_977_23,
+        73.209_519_858_032_02,
+    ],
+    [
+        25.131_126_224_773_41,
+        -12.242_668_952_385_67,
+        -23.070_325_002_871_72,
+    ],
+];
+
+const PLASMA: Poly = [
+    [
+        0.058_732_343_923_997_02,
+        0.023_336_708_925_656_64,
+        0.543_340_182_674_875_4,
+    ],
+    [
+        2.176_514_634_195_958,
+        0.238_383_417_126_018_2,
+        0.753_960_459_978_403_6,
+    ],
+    [
+        -2.689_460_476_458_034,
+        -7.455_851_135_738_909,
+        3.110_799_939_717_086,
+    ],
+    [
+        6.130_348_345_893_603,
+        42.346_188_147_722_7,
+        -28.518_854_653_321_58,
+    ],
+    [
+        -11.107_436_190_622_71,
+        -82.666_311_094_280_45,
+        60.139_847_674_182_63,
+    ],
+    [
+        10.023_065_576_470_65,
+        71.413_617_700_953_5,
+        -54.072_186_555_600_67,
+    ],
+    [
+        -3.658_713_842_777_788,
+        -22.931_534_654_611_49,
+        18.191_907_785_398_28,
+    ],
+];
+
+fn eval_poly(p: &Poly, t: f64) -> [f64; 3] {
+    let mut out = [0.; 3];
+    for (i, o) in out.iter_mut().enumerate() {
+        *o = p
+            .iter()
+            .rev()
+            .fold(0., |acc, c| acc * t + c[i])
+            .clamp(0., 1.);
+    }
+    out
+}
+
+fn hue(t: f64) -> [f64; 3] {
+    let h = 6. * t.rem_euclid(1.);
+    let f = |n: f64| (((n + h) % 6.) - 3.).abs().clamp(1., 2.) - 1.;
+    [f(0.), f(4.), f(2.)]
+}
+
+fn sinebow(t: f64) -> [f64; 3] {
+    let f = |n: f64| (PI * (0.5 - t + n / 3.)).sin().powi(2);
+    [f(0.), f(1.), f(2.)]
+}
+
+/// Maps a value in `[0, 1]` to a color.  The cyclic maps take the same
+/// color at both ends.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum Colormap {
+    #[default]
+    Grayscale,
+    Viridis,
+    Magma,
+    Inferno,
+    Plasma,
+    Hue,
+    Sinebow,
+    Palette,
+}
+
+impl Colormap {
+    pub fn is_cyclic(self) -> bool {
+        matches!(self, Colormap::Hue | Colormap::Sinebow)
+    }
+
+    /// Returns a function that evaluates the colormap.  `palette` is only
+    /// used by `Colormap::Palette`, which falls back to a random two color
+    /// gradient if the palette is empty.
+    pub fn evaluator(self, palette: &Palette) -> Box<dyn Fn(f64) -> [f64; 3] + Send + Sync> {
+        let poly = |p: &'static Poly| -> Box<dyn Fn(f64) -> [f64; 3] + Send + Sync> {
+            Box::new(move |t| eval_poly(p, t.clamp(0., 1.)))
+        };
+        match self {
+            Colormap::Grayscale => Box::new(|t| {
+                let v = t.clamp(0., 1.);
+                [v, v, v]
+            }),
+            Colormap::Viridis => poly(&VIRIDIS),
+            Colormap::Magma => poly(&MAGMA),
+            Colormap::Inferno => poly(&INFERNO),
+            Colormap::Plasma => poly(&PLASMA),
+            Colormap::Hue => Box::new(hue),
+            Colormap::Sinebow => Box::new(sinebow),
+            Colormap::Palette => {
+                let gradient = if palette.is_empty() {
+                    palette.random_gradient()
+                } else {
+                    Gradient::new(palette.0.iter().map(|c| c.0).collect())
+                };
+                Box::new(move |t| gradient.at_f64(t))
+            }
+        }
+    }
+}
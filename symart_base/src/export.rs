@@ -0,0 +1,361 @@
+use image::codecs::png::PngEncoder;
+use image::{DynamicImage, ImageBuffer, ImageError, Luma};
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Seek, Write};
+use std::path::Path;
+use strum_macros::{Display, EnumIter, EnumString};
+use thiserror::Error;
+
+use crate::animate::AnimationFormat;
+use crate::color::{Background, HexColor};
+use crate::layer::{Layer, LayeredImage, Paint};
+use crate::memory;
+use crate::quantize::{quantize, quantize16, Dither, RowQuantizer};
+use crate::symmetry::SymmetryGroup;
+use crate::{DrawResponse, PixelDesign, SymmetryType};
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("{0} does not support {1} bit output")]
+    BitDepth(ExportFormat, u8),
+    #[error("unsupported bit depth {0}")]
+    BadBitDepth(u8),
+    #[error("{0} does not support transparency")]
+    Alpha(ExportFormat),
+    #[error("JPEG quality {0} is not from 1 to 100")]
+    Quality(u8),
+    #[error("support for {0} was not compiled in")]
+    Disabled(ExportFormat),
+    #[error("support for {0} was not compiled in")]
+    AnimationDisabled(AnimationFormat),
+    #[error("unrecognized file extension")]
+    Extension,
+    #[error(transparent)]
+    Image(#[from] ImageError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Png(#[from] png::EncodingError),
+    #[error("{0}")]
+    Design(String),
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum ExportFormat {
+    #[default]
+    Png,
+    Tiff,
+    Jpeg,
+    WebP,
+}
+
+impl ExportFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, ExportError> {
+        let ext = path
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .ok_or(ExportError::Extension)?
+            .to_ascii_lowercase();
+        match ext.as_str() {
+            "png" => Ok(ExportFormat::Png),
+            "tif" | "tiff" => Ok(ExportFormat::Tiff),
+            "jpg" | "jpeg" => Ok(ExportFormat::Jpeg),
+            "webp" => Ok(ExportFormat::WebP),
+            _ => Err(ExportError::Extension),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Png => "png",
+            ExportFormat::Tiff => "tiff",
+            ExportFormat::Jpeg => "jpg",
+            ExportFormat::WebP => "webp",
+        }
+    }
+
+    pub fn supports(self, depth: BitDepth) -> bool {
+        match self {
+            ExportFormat::Png | ExportFormat::Tiff => true,
+            ExportFormat::Jpeg | ExportFormat::WebP => depth == BitDepth::Eight,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub enum BitDepth {
+    #[default]
+    Eight,
+    Sixteen,
+}
+
+impl TryFrom<u8> for BitDepth {
+    type Error = ExportError;
+
+    fn try_from(bits: u8) -> Result<Self, Self::Error> {
+        match bits {
+            8 => Ok(BitDepth::Eight),
+            16 => Ok(BitDepth::Sixteen),
+            _ => Err(ExportError::BadBitDepth(bits)),
+        }
+    }
+}
+
+impl From<BitDepth> for u8 {
+    fn from(d: BitDepth) -> u8 {
+        match d {
+            BitDepth::Eight => 8,
+            BitDepth::Sixteen => 16,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExportOptions {
+    #[serde(default)]
+    pub format: ExportFormat,
+    #[serde(default)]
+    pub bit_depth: BitDepth,
+    #[serde(default)]
+    pub dither: Dither,
+    /// JPEG quality, from 1 to 100.  Other values are rejected.
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+}
+
+fn default_quality() -> u8 {
+    90
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: ExportFormat::default(),
+            bit_depth: BitDepth::default(),
+            dither: Dither::default(),
+            quality: default_quality(),
+        }
+    }
+}
+
+/// Quantizes a floating point image to the requested bit depth and encodes
+/// it.  `periodic` should be set if the image is a tile of a periodic
+/// pattern, so that the dithering wraps around.  JPEG has no alpha channel,
+/// so images with transparency are rejected rather than flattened.
+pub fn export_image<W: Write + Seek>(
+    img: &DynamicImage,
+    periodic: bool,
+    opts: &ExportOptions,
+    w: &mut W,
+) -> Result<(), ExportError> {
+    if !opts.format.supports(opts.bit_depth) {
+        return Err(ExportError::BitDepth(opts.format, opts.bit_depth.into()));
+    }
+    if opts.format == ExportFormat::Jpeg && img.color().has_alpha() {
+        return Err(ExportError::Alpha(opts.format));
+    }
+    if opts.format == ExportFormat::Jpeg && !(1..=100).contains(&opts.quality) {
+        return Err(ExportError::Quality(opts.quality));
+    }
+    let im = match opts.bit_depth {
+        BitDepth::Eight => quantize(img, opts.dither, periodic),
+        BitDepth::Sixteen => quantize16(img, opts.dither, periodic),
+    };
+    match opts.format {
+        ExportFormat::Png => im.write_with_encoder(PngEncoder::new(w))?,
+        #[cfg(feature = "tiff")]
+        ExportFormat::Tiff => im.write_with_encoder(image::codecs::tiff::TiffEncoder::new(w))?,
+        #[cfg(feature = "jpeg")]
+        ExportFormat::Jpeg => {
+            let enc = image::codecs::jpeg::JpegEncoder::new_with_quality(w, opts.quality);
+            DynamicImage::from(im.into_rgb8()).write_with_encoder(enc)?
+        }
+        #[cfg(feature = "webp")]
+        ExportFormat::WebP => {
+            let enc = image::codecs::webp::WebPEncoder::new_lossless(w);
+            im.write_with_encoder(enc)?
+        }
+        #[allow(unreachable_patterns)]
+        f => return Err(ExportError::Disabled(f)),
+    }
+    Ok(())
+}
+
+pub fn export<W: Write + Seek>(
+    response: &DrawResponse,
+    opts: &ExportOptions,
+    w: &mut W,
+) -> Result<(), ExportError> {
+    export_image(&response.im, response.is_periodic(), opts, w)
+}
+
+pub fn export_to_vec(
+    response: &DrawResponse,
+    opts: &ExportOptions,
+) -> Result<Vec<u8>, ExportError> {
+    let mut c = Cursor::new(Vec::new());
+    export(response, opts, &mut c)?;
+    Ok(c.into_inner())
+}
+
+/// Writes an image file.  The format is taken from the file extension
+/// rather than from `opts`.
+pub fn save<P: AsRef<Path>>(
+    response: &DrawResponse,
+    opts: &ExportOptions,
+    path: P,
+) -> Result<(), ExportError> {
+    let opts = ExportOptions {
+        format: ExportFormat::from_path(&path)?,
+        ..opts.clone()
+    };
+    let mut w = BufWriter::new(File::create(path)?);
+    export(response, &opts, &mut w)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Renders a design straight into a PNG encoder, a band of rows at a time,
+/// so that the whole image is never held in memory.  The height of the band
+/// is chosen to fit the memory budget.
+pub fn stream_png<D: PixelDesign, W: Write>(
+    design: &D,
+    bit_depth: BitDepth,
+    dither: Dither,
+    w: W,
+) -> Result<(), ExportError> {
+    let (width, height) = design.size();
+    let f = design
+        .pixel_fn()
+        .map_err(|e| ExportError::Design(e.to_string()))?;
+    let sampler = design.sampler();
+    let mut enc = png::Encoder::new(w, width, height);
+    enc.set_color(png::ColorType::Rgb);
+    let max = match bit_depth {
+        BitDepth::Eight => {
+            enc.set_depth(png::BitDepth::Eight);
+            255.
+        }
+        BitDepth::Sixteen => {
+            enc.set_depth(png::BitDepth::Sixteen);
+            65535.
+        }
+    };
+    let mut writer = enc.write_header()?;
+    let mut stream = writer.stream_writer()?;
+    let mut quantizer = RowQuantizer::new(width, 3, max, dither);
+    let row_len = (width as usize) * 3;
+    let band = memory::band_rows(row_len * std::mem::size_of::<f32>(), height);
+    for y0 in (0..height).step_by(band as usize) {
+        let y1 = (y0 + band).min(height);
+        let data = sampler.render_band(y0, y1, width, &f);
+        for row in data.chunks_exact(row_len) {
+            let q = quantizer.quantize_row(row);
+            let bytes: Vec<u8> = match bit_depth {
+                BitDepth::Eight => q.into_iter().map(|v| v as u8).collect(),
+                BitDepth::Sixteen => q.into_iter().flat_map(u16::to_be_bytes).collect(),
+            };
+            stream.write_all(&bytes)?;
+        }
+    }
+    stream.finish()?;
+    writer.finish()?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct LayerEntry {
+    order: usize,
+    mask: String,
+    source: String,
+    visible: bool,
+    opacity: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<HexColor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gradient: Option<Vec<HexColor>>,
+}
+
+#[derive(Serialize)]
+struct LayerManifest {
+    width: u32,
+    height: u32,
+    background: Background,
+    /// The wallpaper group, if the layers tile the plane.
+    symmetry: Option<SymmetryGroup>,
+    /// Bottom layer first.
+    layers: Vec<LayerEntry>,
+}
+
+fn mask_image(layer: &Layer, width: u32, height: u32, bit_depth: BitDepth) -> DynamicImage {
+    let mask = &layer.mask;
+    let at = |x: u32, y: u32| mask[(x as usize, y as usize)];
+    match bit_depth {
+        BitDepth::Eight => ImageBuffer::from_fn(width, height, |x, y| {
+            Luma([(((at(x, y) as u32) + 128) / 257) as u8])
+        })
+        .into(),
+        BitDepth::Sixteen => ImageBuffer::from_fn(width, height, |x, y| Luma([at(x, y)])).into(),
+    }
+}
+
+/// Writes each layer as a grayscale PNG mask, `name_<order>.png`, together
+/// with a manifest `name.json` giving the color of each layer and the order
+/// in which the layers are composited, so that they can be restyled in
+/// other programs.
+pub fn export_layers(
+    layered: &LayeredImage,
+    bit_depth: BitDepth,
+    dir: &Path,
+    name: &str,
+) -> Result<(), ExportError> {
+    std::fs::create_dir_all(dir)?;
+    let mut entries = Vec::with_capacity(layered.layers.len());
+    for (order, layer) in layered.layers.iter().enumerate() {
+        let mask = format!("{}_{:02}.png", name, order);
+        let im = mask_image(layer, layered.width, layered.height, bit_depth);
+        let mut w = BufWriter::new(File::create(dir.join(&mask))?);
+        im.write_with_encoder(PngEncoder::new(&mut w))?;
+        w.flush()?;
+        let (color, gradient) = match &layer.paint {
+            Paint::Flat(c) => (Some(HexColor(*c)), None),
+            Paint::Gradient(g) => (None, Some(g.colors.iter().map(|c| HexColor(*c)).collect())),
+        };
+        entries.push(LayerEntry {
+            order,
+            mask,
+            source: layer.source.clone(),
+            visible: layer.visible,
+            opacity: layer.opacity,
+            color,
+            gradient,
+        });
+    }
+    let manifest = LayerManifest {
+        width: layered.width,
+        height: layered.height,
+        background: layered.background,
+        symmetry: match layered.sym {
+            SymmetryType::Wrapped(g) => Some(g),
+            SymmetryType::None => None,
+        },
+        layers: entries,
+    };
+    let w = BufWriter::new(File::create(dir.join(format!("{}.json", name)))?);
+    serde_json::to_writer_pretty(w, &manifest).map_err(std::io::Error::from)?;
+    Ok(())
+}
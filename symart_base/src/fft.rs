@@ -1,49 +1,306 @@
-use ndarray::Array2;
-use num_complex::Complex64;
-use num_traits::Zero;
-use rustfft::{Fft, FftDirection, FftPlanner};
-use std::sync::Arc;
-use transpose::transpose_inplace;
+use ndarray::{Array2, ArrayViewMut2, Axis};
+use num_complex::Complex;
+use num_traits::{Float, NumAssign, Zero};
+#[cfg(feature = "threads")]
+use rayon::prelude::*;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use rustfft::{Fft, FftDirection, FftNum, FftPlanner};
+use std::any::{Any, TypeId};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use strum_macros::{Display, EnumIter, EnumString};
 
+/// The floating point types that transforms can be computed in.  `f32`
+/// uses half the memory of `f64` and is faster, at the cost of precision.
+pub trait FftFloat: FftNum + Float + NumAssign {}
+
+impl FftFloat for f32 {}
+
+impl FftFloat for f64 {}
+
+/// The floating point type that a design computes its fields in.  Single
+/// precision halves the memory and is faster, but heavy tailed noise, with
+/// a small stability index, can overflow it.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum Precision {
+    Single,
+    #[default]
+    Double,
+}
+
+/// The number of columns gathered into contiguous memory at a time for the
+/// transforms along the first axis.
+const COLUMN_BLOCK: usize = 16;
+
+/// The number of elements of consecutive rows handed to a thread at a time.
+const ROW_TASK: usize = 1 << 14;
+
+/// The number of plans that `Plan2D::cached` keeps.
+const PLAN_CACHE_SIZE: usize = 8;
+
+/// Plans made by `Plan2D::cached`, keyed by width, height and precision,
+/// from the least to the most recently used.
+type PlanCache = Vec<((usize, usize, TypeId), Box<dyn Any + Send>)>;
+
+static PLANS: OnceLock<Mutex<PlanCache>> = OnceLock::new();
+
+fn plans() -> MutexGuard<'static, PlanCache> {
+    PLANS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Forgets the plans made by `Plan2D::cached`.  Plans that are still in use
+/// are kept until they are dropped.
+pub fn clear_plan_cache() {
+    plans().clear();
+}
+
+/// Plans for transforms of arrays indexed by `(x, y)`, in standard layout,
+/// with the given width and height.  As in `rustfft`, the transforms are
+/// not normalized, so a forward transform followed by an inverse one
+/// multiplies by `width * height`.
 #[derive(Clone)]
-pub struct Plan2D {
-    horizontal: Arc<dyn Fft<f64>>,
-    vertical: Arc<dyn Fft<f64>>,
+pub struct Plan2D<T: FftFloat = f64> {
+    forward_x: Arc<dyn Fft<T>>,
+    inverse_x: Arc<dyn Fft<T>>,
+    forward_y: Arc<dyn Fft<T>>,
+    inverse_y: Arc<dyn Fft<T>>,
+    real_forward_y: Arc<dyn RealToComplex<T>>,
+    real_inverse_y: Arc<dyn ComplexToReal<T>>,
 }
 
-impl Plan2D {
-    pub fn new(planner: &mut FftPlanner<f64>, width: usize, height: usize) -> Self {
+#[cfg(feature = "threads")]
+fn for_each_with_scratch<I, T, F>(items: I, scratch_len: usize, f: F)
+where
+    I: Iterator + Send,
+    I::Item: Send,
+    T: FftFloat,
+    F: Fn(I::Item, &mut [Complex<T>]) + Send + Sync,
+{
+    items
+        .par_bridge()
+        .for_each_init(|| vec![Complex::zero(); scratch_len], |s, item| f(item, s));
+}
+
+#[cfg(not(feature = "threads"))]
+fn for_each_with_scratch<I, T, F>(items: I, scratch_len: usize, f: F)
+where
+    I: Iterator,
+    T: FftFloat,
+    F: Fn(I::Item, &mut [Complex<T>]),
+{
+    let mut scratch = vec![Complex::zero(); scratch_len];
+    items.for_each(|item| f(item, &mut scratch));
+}
+
+fn rows_per_task(len: usize) -> usize {
+    (ROW_TASK / len.max(1)).max(1)
+}
+
+impl<T: FftFloat> Plan2D<T> {
+    /// Plans the transforms with `planner`, which can be shared between
+    /// plans to reuse its work.
+    pub fn new(planner: &mut FftPlanner<T>, width: usize, height: usize) -> Self {
+        let mut real_planner = RealFftPlanner::new();
         Self {
-            horizontal: planner.plan_fft(width, FftDirection::Forward),
-            vertical: planner.plan_fft(height, FftDirection::Forward),
+            forward_x: planner.plan_fft(width, FftDirection::Forward),
+            inverse_x: planner.plan_fft(width, FftDirection::Inverse),
+            forward_y: planner.plan_fft(height, FftDirection::Forward),
+            inverse_y: planner.plan_fft(height, FftDirection::Inverse),
+            real_forward_y: real_planner.plan_fft_forward(height),
+            real_inverse_y: real_planner.plan_fft_inverse(height),
         }
     }
 
-    pub fn apply(&self, arr: &mut Array2<Complex64>) {
-        let w = arr.shape()[0];
-        let h = arr.shape()[1];
-        let sdim = *[
-            self.horizontal.get_inplace_scratch_len(),
-            self.vertical.get_inplace_scratch_len(),
-            w,
-            h,
-        ]
-        .iter()
-        .max()
-        .unwrap();
-        let mut scratch = vec![Zero::zero(); sdim];
-        let flat = arr.as_slice_mut().unwrap();
-        self.vertical.process_with_scratch(flat, &mut scratch);
-        transpose_inplace(flat, &mut scratch, h, w);
-        self.horizontal.process_with_scratch(flat, &mut scratch);
-        transpose_inplace(flat, &mut scratch, w, h);
+    /// Like `new`, but shares the plans with every other caller in the
+    /// process that asks for the same dimensions and precision, so repeated
+    /// renders at one size skip planning.  Only the most recently used
+    /// sizes are kept.
+    pub fn cached(width: usize, height: usize) -> Self {
+        let key = (width, height, TypeId::of::<T>());
+        if let Some(plan) = Self::lookup(&mut plans(), key) {
+            return plan;
+        }
+        // Planning can be slow, so it is done without holding the lock.
+        let plan = Self::new(&mut FftPlanner::new(), width, height);
+        let mut plans = plans();
+        if let Some(plan) = Self::lookup(&mut plans, key) {
+            return plan;
+        }
+        if plans.len() == PLAN_CACHE_SIZE {
+            plans.remove(0);
+        }
+        plans.push((key, Box::new(plan.clone())));
+        plan
+    }
+
+    /// Finds the plan for `key` in the cache and marks it as the most
+    /// recently used.
+    fn lookup(plans: &mut PlanCache, key: (usize, usize, TypeId)) -> Option<Self> {
+        let i = plans.iter().position(|(k, _)| *k == key)?;
+        let entry = plans.remove(i);
+        let plan = entry.1.downcast_ref::<Self>().unwrap().clone();
+        plans.push(entry);
+        Some(plan)
     }
 
     pub fn width(&self) -> usize {
-        self.horizontal.len()
+        self.forward_x.len()
     }
 
     pub fn height(&self) -> usize {
-        self.vertical.len()
+        self.forward_y.len()
+    }
+
+    /// The height of the spectrum of a real array, which holds the
+    /// frequencies in `y` from 0 to `height / 2`.  The others are the
+    /// complex conjugates of these.
+    pub fn spectrum_height(&self) -> usize {
+        self.height() / 2 + 1
+    }
+
+    pub fn process(&self, arr: &mut Array2<Complex<T>>, direction: FftDirection) {
+        let (x, y) = match direction {
+            FftDirection::Forward => (&self.forward_x, &self.forward_y),
+            FftDirection::Inverse => (&self.inverse_x, &self.inverse_y),
+        };
+        self.rows(arr, y);
+        self.columns(arr, x);
+    }
+
+    pub fn forward(&self, arr: &mut Array2<Complex<T>>) {
+        self.process(arr, FftDirection::Forward)
+    }
+
+    pub fn inverse(&self, arr: &mut Array2<Complex<T>>) {
+        self.process(arr, FftDirection::Inverse)
+    }
+
+    /// The forward transform, under its older name.
+    pub fn apply(&self, arr: &mut Array2<Complex<T>>) {
+        self.forward(arr)
+    }
+
+    /// The forward transform of a real array, with `spectrum_height`
+    /// columns.  The array is used as scratch space, so it is consumed.
+    pub fn forward_real(&self, mut arr: Array2<T>) -> Array2<Complex<T>> {
+        let (h, hc) = (self.height(), self.spectrum_height());
+        let mut out = Array2::zeros((self.width(), hc));
+        let k = rows_per_task(h);
+        let fft = &self.real_forward_y;
+        let input = arr.as_slice_mut().unwrap().chunks_mut(h * k);
+        let output = out.as_slice_mut().unwrap().chunks_mut(hc * k);
+        for_each_with_scratch(input.zip(output), fft.get_scratch_len(), |(i, o), s| {
+            for (row, spec) in i.chunks_mut(h).zip(o.chunks_mut(hc)) {
+                fft.process_with_scratch(row, spec, s).unwrap();
+            }
+        });
+        drop(arr);
+        self.columns(&mut out, &self.forward_x);
+        out
+    }
+
+    /// The inverse transform of the spectrum of a real array, as returned
+    /// by `forward_real`.  Imaginary parts that must vanish for the result
+    /// to be real are ignored.  The spectrum is used as scratch space, so
+    /// it is consumed.
+    pub fn inverse_real(&self, mut spectrum: Array2<Complex<T>>) -> Array2<T> {
+        let (h, hc) = (self.height(), self.spectrum_height());
+        self.columns(&mut spectrum, &self.inverse_x);
+        let mut out = Array2::zeros((self.width(), h));
+        let k = rows_per_task(h);
+        let fft = &self.real_inverse_y;
+        let input = spectrum.as_slice_mut().unwrap().chunks_mut(hc * k);
+        let output = out.as_slice_mut().unwrap().chunks_mut(h * k);
+        for_each_with_scratch(input.zip(output), fft.get_scratch_len(), |(i, o), s| {
+            for (spec, row) in i.chunks_mut(hc).zip(o.chunks_mut(h)) {
+                spec[0].im = T::zero();
+                if h % 2 == 0 {
+                    spec[hc - 1].im = T::zero();
+                }
+                fft.process_with_scratch(spec, row, s).unwrap();
+            }
+        });
+        out
+    }
+
+    /// Transforms each row, along the second axis, which is contiguous.
+    fn rows(&self, arr: &mut Array2<Complex<T>>, fft: &Arc<dyn Fft<T>>) {
+        let len = fft.len();
+        let rows = arr
+            .as_slice_mut()
+            .unwrap()
+            .chunks_mut(len * rows_per_task(len));
+        for_each_with_scratch(rows, fft.get_inplace_scratch_len(), |r, s| {
+            fft.process_with_scratch(r, s)
+        });
+    }
+
+    /// Transforms each column, along the first axis, by copying blocks of
+    /// columns into contiguous memory rather than transposing the array.
+    fn columns(&self, arr: &mut Array2<Complex<T>>, fft: &Arc<dyn Fft<T>>) {
+        let len = fft.len();
+        let scratch_len = COLUMN_BLOCK * len + fft.get_inplace_scratch_len();
+        let blocks = arr.axis_chunks_iter_mut(Axis(1), COLUMN_BLOCK);
+        for_each_with_scratch(blocks, scratch_len, |mut block: ArrayViewMut2<_>, s| {
+            let (buf, s) = s.split_at_mut(COLUMN_BLOCK * len);
+            let buf = &mut buf[..block.shape()[1] * len];
+            for ((x, j), v) in block.indexed_iter() {
+                buf[j * len + x] = *v;
+            }
+            fft.process_with_scratch(buf, s);
+            for ((x, j), v) in block.indexed_iter_mut() {
+                *v = buf[j * len + x];
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(width: usize, height: usize) -> Array2<f64> {
+        Array2::from_shape_fn((width, height), |(x, y)| {
+            ((3 * x + 7 * y * y) % 11) as f64 - 5.
+        })
+    }
+
+    #[test]
+    fn complex_round_trip() {
+        let (w, h) = (6, 5);
+        let plan = Plan2D::new(&mut FftPlanner::new(), w, h);
+        let orig = input(w, h).mapv(|v| Complex::new(v, 1. - v));
+        let mut arr = orig.clone();
+        plan.forward(&mut arr);
+        plan.inverse(&mut arr);
+        for (a, b) in arr.iter().zip(&orig) {
+            assert!((a - b.scale((w * h) as f64)).norm() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn real_round_trip() {
+        for (w, h) in [(6, 5), (4, 8)] {
+            let plan = Plan2D::<f64>::cached(w, h);
+            let orig = input(w, h);
+            let out = plan.inverse_real(plan.forward_real(orig.clone()));
+            for (a, b) in out.iter().zip(&orig) {
+                assert!((a - b * ((w * h) as f64)).abs() < 1e-9);
+            }
+        }
     }
 }
@@ -0,0 +1,597 @@
+//! Periodic random fields: Lévy stable noise, made symmetric under a
+//! wallpaper group and shaped by a filter on its power spectrum.
+
+use ndarray::Array2;
+use num_complex::Complex;
+use num_traits::Zero;
+use rand::distributions::Distribution;
+use rand::Rng;
+use std::f64::consts::PI;
+use std::ops::AddAssign;
+use thiserror::Error;
+
+use crate::animate::{mix, pick};
+use crate::canvas::WrapDimension;
+use crate::fft::{FftFloat, Plan2D};
+use crate::random::Levy;
+use crate::rng::sample_fn;
+use crate::symmetric_canvas::orbits;
+use crate::symmetry::{transformations, GridNorm, SymmetryGroup};
+
+/// How the amplitude of the noise depends on its frequency.  Frequencies are
+/// measured in cycles per tile, so a filter gives the same texture at any
+/// size.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SpectralFilter {
+    /// `r^(-exponent/2)`, where `r` is the symbol of the discrete Laplacian.
+    #[default]
+    PowerLaw,
+    /// A Gaussian bump around a non-negative `frequency`, with a positive
+    /// standard deviation `width`.
+    BandPass { frequency: f64, width: f64 },
+    /// Only the frequencies between `inner` and `outer`, in either order.
+    Ring { inner: f64, outer: f64 },
+    /// A power law with features stretched by `aspect` along the direction
+    /// `angle` degrees counterclockwise from the horizontal.
+    Anisotropic { angle: f64, aspect: f64 },
+    /// Amplitudes at evenly spaced frequencies from 0 to the highest
+    /// frequency of the full size image, interpolated linearly.
+    Radial { table: Vec<f64> },
+}
+
+#[derive(Error, Debug)]
+pub enum FilterError {
+    #[error("Bad filter parameters")]
+    BadParam,
+}
+
+impl SpectralFilter {
+    /// Checks that the amplitudes of the filter are finite: a band pass
+    /// filter needs a finite, non-negative frequency and a positive width.
+    pub fn validate(&self) -> Result<(), FilterError> {
+        match self {
+            SpectralFilter::BandPass { frequency, width } => {
+                if frequency.is_finite() && *frequency >= 0. && width.is_finite() && *width > 0. {
+                    Ok(())
+                } else {
+                    Err(FilterError::BadParam)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The amplitude at the frequency `(fx, fy)` of a grid with the given
+    /// norm.  `nyquist` is the highest frequency along each axis of the
+    /// grid.  The power law uses the Laplacian of the lattice, so that it is
+    /// isotropic for the symmetry group of the noise.
+    pub fn amplitude(
+        &self,
+        norm: GridNorm,
+        fx: f64,
+        fy: f64,
+        exponent: f64,
+        nyquist: (f64, f64),
+    ) -> f64 {
+        let (ox, oy) = orthonormal_frequency(norm, fx, fy);
+        let f2 = ox * ox + oy * oy;
+        match self {
+            SpectralFilter::PowerLaw => {
+                let (kx, ky) = (PI * fx / nyquist.0, PI * fy / nyquist.1);
+                // The constant keeps the symbol positive at zero frequency,
+                // where the Laplacian vanishes.
+                let c = f64::cos(PI / nyquist.0.max(nyquist.1));
+                let r = match norm {
+                    GridNorm::Square => 3. - c - f64::cos(kx) - f64::cos(ky),
+                    GridNorm::Hexagonal => 4. - c - f64::cos(kx) - f64::cos(ky) - f64::cos(kx - ky),
+                };
+                r.powf(-exponent / 2.)
+            }
+            SpectralFilter::BandPass { frequency, width } => {
+                let d = f2.sqrt() - frequency;
+                f64::exp(-d * d / (2. * width * width))
+            }
+            SpectralFilter::Ring { inner, outer } => {
+                let f = f2.sqrt();
+                if (inner.min(*outer)..=inner.max(*outer)).contains(&f) {
+                    1.
+                } else {
+                    0.
+                }
+            }
+            SpectralFilter::Anisotropic { angle, aspect } => {
+                let (s, c) = angle.to_radians().sin_cos();
+                // The first array axis is image x, and image y points down.
+                let along = ox * c - oy * s;
+                let across = ox * s + oy * c;
+                let q = (aspect * along).powi(2) + across * across;
+                (1. + q).powf(-exponent / 2.)
+            }
+            SpectralFilter::Radial { table } => match table.len() {
+                0 => 1.,
+                1 => table[0],
+                n => {
+                    let nyquist = nyquist.0.max(nyquist.1);
+                    let u = (f2.sqrt() / nyquist).min(1.) * ((n - 1) as f64);
+                    let i = (u as usize).min(n - 2);
+                    mix(table[i], table[i + 1], u - (i as f64))
+                }
+            },
+        }
+    }
+
+    /// The filter a fraction `t` of the way to `other`.  Filters of
+    /// different kinds, or tables of different lengths, switch halfway.
+    pub fn mix(&self, other: &SpectralFilter, t: f64) -> SpectralFilter {
+        use SpectralFilter::*;
+        match (self, other) {
+            (
+                BandPass {
+                    frequency: f1,
+                    width: w1,
+                },
+                BandPass {
+                    frequency: f2,
+                    width: w2,
+                },
+            ) => BandPass {
+                frequency: mix(*f1, *f2, t),
+                width: mix(*w1, *w2, t),
+            },
+            (
+                Ring {
+                    inner: i1,
+                    outer: o1,
+                },
+                Ring {
+                    inner: i2,
+                    outer: o2,
+                },
+            ) => Ring {
+                inner: mix(*i1, *i2, t),
+                outer: mix(*o1, *o2, t),
+            },
+            (
+                Anisotropic {
+                    angle: a1,
+                    aspect: r1,
+                },
+                Anisotropic {
+                    angle: a2,
+                    aspect: r2,
+                },
+            ) => Anisotropic {
+                angle: mix(*a1, *a2, t),
+                aspect: mix(*r1, *r2, t),
+            },
+            (Radial { table: t1 }, Radial { table: t2 }) if t1.len() == t2.len() => Radial {
+                table: t1.iter().zip(t2).map(|(a, b)| mix(*a, *b, t)).collect(),
+            },
+            _ => pick(self, other, t).clone(),
+        }
+    }
+}
+
+/// Converts a frequency of a grid to orthonormal coordinates, so that
+/// filters that depend on its length are isotropic.  The hexagonal grid has
+/// axes 60 degrees apart, and its frequencies keep their length along the
+/// first axis.
+pub fn orthonormal_frequency(norm: GridNorm, fx: f64, fy: f64) -> (f64, f64) {
+    match norm {
+        GridNorm::Square => (fx, fy),
+        GridNorm::Hexagonal => (0.5 * f64::sqrt(3.) * fx, fy - 0.5 * fx),
+    }
+}
+
+/// A description of a random field.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldParams {
+    pub filter: SpectralFilter,
+    /// The exponent of the power law filters.
+    pub exponent: f64,
+    /// The stability index of the noise, from 0 to 2.  2 gives Gaussian
+    /// noise, and smaller values give heavier tails.
+    pub alpha: f64,
+    /// The group the field is invariant under, which requires a square
+    /// grid.  Without one the grid can have any shape.
+    pub symmetry: Option<SymmetryGroup>,
+    /// The lattice whose Laplacian the power law uses, and whose frequencies
+    /// the other filters measure.
+    pub lattice: GridNorm,
+    /// Frequencies are divided by this before the filter is applied, so the
+    /// features of the field are this many times smaller.
+    pub frequency: f64,
+    /// Whether the real and imaginary parts are independent fields, rather
+    /// than the imaginary part being zero.
+    pub complex: bool,
+    /// The noise is drawn for a grid this many times finer than the plan
+    /// and summed over blocks, so that a small field has the same large
+    /// scale structure as a large one with the same seed.
+    pub reduction: usize,
+}
+
+impl FieldParams {
+    /// A real Gaussian field with the power law filter, on the lattice that
+    /// suits the symmetry.
+    pub fn new(symmetry: Option<SymmetryGroup>) -> Self {
+        Self {
+            filter: SpectralFilter::PowerLaw,
+            exponent: 2.,
+            alpha: 2.,
+            symmetry,
+            lattice: symmetry.map_or(GridNorm::Square, GridNorm::from_symmetry),
+            frequency: 1.,
+            complex: false,
+            reduction: 1,
+        }
+    }
+}
+
+fn levy<T: FftFloat, R: Rng + ?Sized>(rng: &mut R, alpha: f64) -> T {
+    T::from_f64(Levy { alpha }.sample(rng)).unwrap()
+}
+
+/// Makes independent noise invariant under the group by summing it over
+/// each orbit.  A point on a mirror line or a rotation centre has a smaller
+/// orbit, so the sum is scaled as though the orbit had as many points as a
+/// generic one, which for stable noise of index `alpha` gives every point
+/// the same distribution.  `arr` must be square.
+pub fn symmetrize<E: FieldValue>(arr: &Array2<E>, sym: SymmetryGroup, alpha: f64) -> Array2<E> {
+    let mut out = Array2::zeros(arr.raw_dim());
+    let dims = WrapDimension::new_from_shape(arr.shape());
+    let hsz = arr.shape()[0] / 2;
+    let transforms = transformations(sym, hsz as i32);
+    for orbit in orbits(sym, hsz as u32) {
+        let pt = orbit.representative;
+        let images = || transforms.iter().map(|t| dims.compute_index(&t.apply(&pt)));
+        // Each point of the orbit is the image of `stabilizer` transformations.
+        let mut sum = E::zero();
+        images().for_each(|i| sum += arr[i]);
+        let s = orbit.stabilizer as f64;
+        let v = sum.scaled(s.powf(1. / alpha) / s);
+        images().for_each(|i| out[i] = v);
+    }
+    out
+}
+
+fn noise_with<T, E, R, G>(
+    plan: &Plan2D<T>,
+    rng: &mut R,
+    params: &FieldParams,
+    mut gen: G,
+) -> Array2<E>
+where
+    T: FftFloat,
+    E: FieldValue,
+    R: Rng + ?Sized,
+    G: FnMut(&mut R) -> E,
+{
+    let (w, h, r) = (plan.width(), plan.height(), params.reduction);
+    let noise = if r == 1 {
+        Array2::from_shape_fn((w, h), |_| gen(rng))
+    } else {
+        let mut blocks = Array2::<E>::zeros((w, h));
+        for x in 0..w * r {
+            for y in 0..h * r {
+                blocks[(x / r, y / r)] += gen(rng);
+            }
+        }
+        blocks
+    };
+    match params.symmetry {
+        Some(sym) => symmetrize(&noise, sym, params.alpha),
+        None => noise,
+    }
+}
+
+/// Unfiltered noise the size of the plan, made symmetric if the parameters
+/// give a symmetry.
+pub fn white_noise<T: FftFloat, R: Rng + ?Sized>(
+    plan: &Plan2D<T>,
+    rng: &mut R,
+    params: &FieldParams,
+) -> Array2<Complex<T>> {
+    let alpha = params.alpha;
+    noise_with(plan, rng, params, |rng| {
+        let re = levy(rng, alpha);
+        let im = if params.complex {
+            levy(rng, alpha)
+        } else {
+            T::zero()
+        };
+        Complex::new(re, im)
+    })
+}
+
+/// Like `white_noise` for a real field, which draws the same numbers.
+pub fn white_noise_real<T: FftFloat, R: Rng + ?Sized>(
+    plan: &Plan2D<T>,
+    rng: &mut R,
+    params: &FieldParams,
+) -> Array2<T> {
+    noise_with(plan, rng, params, |rng| levy(rng, params.alpha))
+}
+
+/// The amplitude of the filter at each index of the spectrum.  The filter
+/// is that of the grid `reduction` times finer than the plan, at the
+/// frequencies that the plan can represent.
+fn filter_symbol<'a, T: FftFloat>(
+    plan: &Plan2D<T>,
+    params: &'a FieldParams,
+) -> impl Fn(usize, usize) -> T + 'a {
+    let (filter, exponent) = (&params.filter, params.exponent);
+    let (reduction, norm, s) = (params.reduction, params.lattice, params.frequency);
+    let (w, h) = (plan.width(), plan.height());
+    let signed = |i: usize, n: usize| {
+        if i <= n / 2 {
+            i as f64
+        } else {
+            (i as f64) - (n as f64)
+        }
+    };
+    let nyquist = (
+        0.5 * ((w * reduction) as f64),
+        0.5 * ((h * reduction) as f64),
+    );
+    move |x, y| {
+        // The highest frequency of an even dimension is its own negative.
+        // Its sign follows the other coordinate, so that the filter is even
+        // and keeps real noise real.
+        let (mut fx, mut fy) = (signed(x, w), signed(y, h));
+        if 2 * x == w && fy < 0. {
+            fx = -fx;
+        }
+        if 2 * y == h && fx < 0. {
+            fy = -fy;
+        }
+        let a = filter.amplitude(norm, fx / s, fy / s, exponent, nyquist);
+        T::from_f64(a).unwrap()
+    }
+}
+
+/// Filters noise in place.  The filter is even, so the second transform is
+/// a forward one too, which gives the filtered noise reflected through the
+/// origin and scaled by the number of points.
+pub fn apply_filter<T: FftFloat>(
+    plan: &Plan2D<T>,
+    arr: &mut Array2<Complex<T>>,
+    params: &FieldParams,
+) {
+    plan.forward(arr);
+    let symbol = filter_symbol(plan, params);
+    for ((x, y), d) in arr.indexed_iter_mut() {
+        *d = d.scale(symbol(x, y));
+    }
+    plan.forward(arr);
+}
+
+/// Filters real noise with real transforms, in about half the time and
+/// memory.  The result is the real part of what `apply_filter` gives for
+/// the same noise: conjugating the spectrum before the inverse transform
+/// has the same effect as a second forward transform.
+pub fn filter_real<T: FftFloat>(
+    plan: &Plan2D<T>,
+    arr: Array2<T>,
+    params: &FieldParams,
+) -> Array2<T> {
+    let mut spectrum = plan.forward_real(arr);
+    let symbol = filter_symbol(plan, params);
+    for ((x, y), d) in spectrum.indexed_iter_mut() {
+        *d = d.scale(symbol(x, y)).conj();
+    }
+    plan.inverse_real(spectrum)
+}
+
+/// A filtered field the size of the plan, drawn from the generator of the
+/// current thread.
+pub fn random_field<T: FftFloat>(plan: &Plan2D<T>, params: &FieldParams) -> Array2<Complex<T>> {
+    let mut arr = sample_fn(|rng| white_noise(plan, rng, params));
+    apply_filter(plan, &mut arr, params);
+    arr
+}
+
+/// A filtered real field, which is the same as the real part of
+/// `random_field` for parameters that are not complex.
+pub fn random_real_field<T: FftFloat>(plan: &Plan2D<T>, params: &FieldParams) -> Array2<T> {
+    let arr = sample_fn(|rng| white_noise_real(plan, rng, params));
+    filter_real(plan, arr, params)
+}
+
+/// The values of real and complex fields.
+pub trait FieldValue: Copy + Zero + AddAssign {
+    fn magnitude_sqr(&self) -> f64;
+    fn scaled(self, k: f64) -> Self;
+}
+
+impl<T: FftFloat> FieldValue for T {
+    fn magnitude_sqr(&self) -> f64 {
+        (*self * *self).to_f64().unwrap()
+    }
+
+    fn scaled(self, k: f64) -> Self {
+        self * T::from_f64(k).unwrap()
+    }
+}
+
+impl<T: FftFloat> FieldValue for Complex<T> {
+    fn magnitude_sqr(&self) -> f64 {
+        Complex::norm_sqr(self).to_f64().unwrap()
+    }
+
+    fn scaled(self, k: f64) -> Self {
+        Complex::scale(&self, T::from_f64(k).unwrap())
+    }
+}
+
+/// Fields at doubling frequencies, summed so that detail nests at several
+/// scales.  Each octave is scaled to the same mean square, and then the
+/// amplitude falls by `gain` from each octave to the next.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Octaves {
+    pub count: usize,
+    pub gain: f64,
+    /// The exponent of each octave, for the power law filters.  If it is
+    /// not empty, it gives the number of octaves rather than `count`.
+    #[serde(default)]
+    pub exponents: Vec<f64>,
+}
+
+impl Default for Octaves {
+    fn default() -> Self {
+        Self {
+            count: 1,
+            gain: 0.5,
+            exponents: Vec::new(),
+        }
+    }
+}
+
+impl Octaves {
+    pub fn num_octaves(&self) -> usize {
+        if self.exponents.is_empty() {
+            self.count.max(1)
+        } else {
+            self.exponents.len()
+        }
+    }
+
+    /// The parameters and the amplitude of each octave of a field.
+    pub fn octaves(&self, base: &FieldParams) -> Vec<(FieldParams, f64)> {
+        (0..self.num_octaves())
+            .map(|k| {
+                let params = FieldParams {
+                    exponent: self.exponents.get(k).copied().unwrap_or(base.exponent),
+                    frequency: base.frequency * f64::powi(2., k as i32),
+                    ..base.clone()
+                };
+                (params, self.gain.powi(k as i32))
+            })
+            .collect()
+    }
+
+    /// The octaves a fraction `t` of the way to `other`, which must have the
+    /// same number of octaves.
+    pub fn mix(&self, other: &Octaves, t: f64) -> Octaves {
+        let exponents = if self.exponents.len() == other.exponents.len() {
+            self.exponents
+                .iter()
+                .zip(&other.exponents)
+                .map(|(a, b)| mix(*a, *b, t))
+                .collect()
+        } else {
+            pick(&self.exponents, &other.exponents, t).clone()
+        };
+        Octaves {
+            count: pick(self.count, other.count, t),
+            gain: mix(self.gain, other.gain, t),
+            exponents,
+        }
+    }
+}
+
+/// The sum of the octaves of a field, given with their amplitudes, each
+/// scaled to the same mean square first.  The octaves are consumed one at a
+/// time, so only two are held at once.  A single octave is returned as it
+/// is.
+pub fn octave_sum<E, I>(octaves: I) -> Array2<E>
+where
+    E: FieldValue,
+    I: IntoIterator<Item = (Array2<E>, f64)>,
+{
+    let mut octaves = octaves.into_iter();
+    let (first, w0) = octaves.next().expect("a field needs at least one octave");
+    let mut rest = octaves.peekable();
+    if rest.peek().is_none() {
+        return first;
+    }
+    let rms = |arr: &Array2<E>| {
+        (arr.iter().map(E::magnitude_sqr).sum::<f64>() / (arr.len() as f64))
+            .sqrt()
+            .max(f64::MIN_POSITIVE)
+    };
+    let k = w0 / rms(&first);
+    let mut sum = first;
+    sum.mapv_inplace(|v| v.scaled(k));
+    for (arr, w) in rest {
+        let k = w / rms(&arr);
+        sum.zip_mut_with(&arr, |s, &v| *s += v.scaled(k));
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::with_seed;
+    use strum::IntoEnumIterator;
+
+    #[test]
+    fn symmetrize_is_invariant() {
+        let n = 12;
+        for sym in SymmetryGroup::iter() {
+            let noise = with_seed(1, || {
+                sample_fn(|rng| Array2::from_shape_fn((n, n), |_| rng.gen::<f64>()))
+            });
+            let arr = symmetrize(&noise, sym, 1.5);
+            let dims = WrapDimension::new_from_shape(arr.shape());
+            for t in transformations(sym, (n / 2) as i32) {
+                for ((y, x), &v) in arr.indexed_iter() {
+                    let pt = crate::canvas::Coord::new(x as i32, y as i32);
+                    assert_eq!(arr[dims.compute_index(&t.apply(&pt))], v, "{sym}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn symmetrize_keeps_variance_on_stabilized_points() {
+        let (n, runs) = (12, 300);
+        let dims = WrapDimension::new(n as i32, n as i32);
+        for sym in SymmetryGroup::iter() {
+            // One point of each orbit, split by whether it is stabilized.
+            let (fixed, generic): (Vec<_>, Vec<_>) =
+                orbits(sym, (n / 2) as u32).partition(|o| o.stabilizer > 1);
+            if fixed.is_empty() {
+                continue;
+            }
+            let variance = |arrs: &[Array2<f64>], pts: &[crate::symmetric_canvas::Orbit]| {
+                let sum: f64 = arrs
+                    .iter()
+                    .flat_map(|a| pts.iter().map(|o| a[dims.compute_index(&o.representative)]))
+                    .map(|v| v * v)
+                    .sum();
+                sum / ((arrs.len() * pts.len()) as f64)
+            };
+            let arrs: Vec<_> = with_seed(2, || {
+                sample_fn(|rng| {
+                    (0..runs)
+                        .map(|_| {
+                            let noise = Array2::from_shape_fn((n, n), |_| {
+                                rng.sample::<f64, _>(rand_distr::StandardNormal)
+                            });
+                            symmetrize(&noise, sym, 2.)
+                        })
+                        .collect()
+                })
+            });
+            let ratio = variance(&arrs, &fixed) / variance(&arrs, &generic);
+            assert!((ratio - 1.).abs() < 0.15, "{sym}: {ratio}");
+        }
+    }
+
+    #[test]
+    fn real_field_is_real_part() {
+        let plan = Plan2D::<f64>::cached(16, 10);
+        let params = FieldParams {
+            reduction: 2,
+            ..FieldParams::new(None)
+        };
+        let complex = with_seed(7, || random_field(&plan, &params));
+        let real = with_seed(7, || random_real_field(&plan, &params));
+        let scale = real.iter().map(|v| v.abs()).fold(0., f64::max);
+        for (c, r) in complex.iter().zip(&real) {
+            assert!((c.re - r).abs() <= 1e-9 * scale);
+        }
+    }
+}
@@ -1,7 +1,29 @@
+use crate::color::{lerp, Background, Gradient, Palette};
 use crate::random;
 use crate::rng::sample;
-use image::{Rgb, RgbImage};
+use crate::{DrawResponse, SymmetryType};
+use image::{DynamicImage, Rgb, Rgb32FImage, RgbImage, Rgba, Rgba32FImage};
 use ndarray::Array2;
+use strum_macros::{Display, EnumIter, EnumString};
+use thiserror::Error;
+
+/// A pixel value of a layer mask.
+pub trait Intensity: Copy {
+    /// The opacity, between 0 and 1.
+    fn alpha(self) -> f32;
+}
+
+impl Intensity for u8 {
+    fn alpha(self) -> f32 {
+        (self as f32) / 255.
+    }
+}
+
+impl Intensity for u16 {
+    fn alpha(self) -> f32 {
+        (self as f32) / 65535.
+    }
+}
 
 fn merge_color(old: u8, new: u8, alpha: u8) -> u8 {
     let a2 = (!alpha) as u16;
@@ -9,6 +31,174 @@ fn merge_color(old: u8, new: u8, alpha: u8) -> u8 {
     (tot / 255) as u8
 }
 
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum Shading {
+    /// Each layer is painted in a single color.
+    #[default]
+    Flat,
+    /// Each layer's intensity is mapped through a two color gradient, going
+    /// from the first color at full intensity to the second at the edges.
+    Gradient,
+}
+
+/// How the pixels of a layer are colored.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Paint {
+    Flat(Rgb<u8>),
+    Gradient(Gradient),
+}
+
+impl Paint {
+    pub fn random(shading: Shading, palette: &Palette) -> Self {
+        match shading {
+            Shading::Flat => Paint::Flat(palette.random_color()),
+            Shading::Gradient => Paint::Gradient(palette.random_gradient()),
+        }
+    }
+
+    /// The paint a fraction `t` of the way to `other`.  Paints of different
+    /// kinds, or gradients with different numbers of stops, switch halfway.
+    pub fn mix(&self, other: &Paint, t: f64) -> Paint {
+        match (self, other) {
+            (Paint::Flat(a), Paint::Flat(b)) => Paint::Flat(lerp(*a, *b, t)),
+            (Paint::Gradient(a), Paint::Gradient(b)) if a.colors.len() == b.colors.len() => {
+                let colors = a.colors.iter().zip(&b.colors);
+                Paint::Gradient(Gradient::new(
+                    colors.map(|(a, b)| lerp(*a, *b, t)).collect(),
+                ))
+            }
+            _ if t < 0.5 => self.clone(),
+            _ => other.clone(),
+        }
+    }
+
+    /// The color of a pixel with the given opacity, with channels in
+    /// `[0, 1]`.
+    pub fn color(&self, alpha: f32) -> [f32; 3] {
+        match self {
+            Paint::Flat(c) => c.0.map(|v| (v as f32) / 255.),
+            Paint::Gradient(g) => g.at_f64(1. - (alpha as f64)).map(|v| v as f32),
+        }
+    }
+}
+
+impl From<Rgb<u8>> for Paint {
+    fn from(c: Rgb<u8>) -> Self {
+        Paint::Flat(c)
+    }
+}
+
+/// A layer mask together with the paint that it is composited with.
+#[derive(Clone, Debug)]
+pub struct Layer {
+    pub mask: Array2<u16>,
+    pub paint: Paint,
+    /// What generated the layer, such as the name of a Lines design.
+    pub source: String,
+    pub visible: bool,
+    /// Multiplies the mask when the layer is composited.
+    pub opacity: f32,
+}
+
+impl Layer {
+    pub fn new(mask: Array2<u16>, paint: Paint, source: impl Into<String>) -> Self {
+        Self {
+            mask,
+            paint,
+            source: source.into(),
+            visible: true,
+            opacity: 1.,
+        }
+    }
+
+    /// Widens an 8-bit mask to 16 bits.
+    pub fn from_u8(mask: &Array2<u8>, paint: Paint, source: impl Into<String>) -> Self {
+        Self::new(mask.mapv(|v| (v as u16) * 257), paint, source)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LayerError {
+    #[error("layer {0} does not exist")]
+    NoLayer(usize),
+    #[error("the new order is not a permutation of the layers")]
+    BadOrder,
+}
+
+/// The layers of a design before they are merged, bottom first.  The
+/// layers can be recolored, reordered and hidden, and then flattened again,
+/// without regenerating the masks.
+#[derive(Clone, Debug)]
+pub struct LayeredImage {
+    pub width: u32,
+    pub height: u32,
+    pub background: Background,
+    pub layers: Vec<Layer>,
+    pub sym: SymmetryType,
+}
+
+impl LayeredImage {
+    /// Gives every layer a new random paint from `palette`, keeping the kind
+    /// of shading it had.
+    pub fn recolor(&mut self, palette: &Palette) {
+        for layer in &mut self.layers {
+            let shading = match layer.paint {
+                Paint::Flat(_) => Shading::Flat,
+                Paint::Gradient(_) => Shading::Gradient,
+            };
+            layer.paint = Paint::random(shading, palette);
+        }
+    }
+
+    /// Rearranges the layers so that the new `i`th layer is the old
+    /// `order[i]`th layer.
+    pub fn reorder(&mut self, order: &[usize]) -> Result<(), LayerError> {
+        let n = self.layers.len();
+        let mut seen = vec![false; n];
+        if order.len() != n {
+            return Err(LayerError::BadOrder);
+        }
+        for &i in order {
+            if i >= n || seen[i] {
+                return Err(LayerError::BadOrder);
+            }
+            seen[i] = true;
+        }
+        let mut old: Vec<Option<Layer>> = self.layers.drain(..).map(Some).collect();
+        self.layers = order.iter().map(|&i| old[i].take().unwrap()).collect();
+        Ok(())
+    }
+
+    /// Shows a hidden layer or hides a visible one.  Returns whether the
+    /// layer is now visible.
+    pub fn toggle_layer(&mut self, i: usize) -> Result<bool, LayerError> {
+        let layer = self.layers.get_mut(i).ok_or(LayerError::NoLayer(i))?;
+        layer.visible = !layer.visible;
+        Ok(layer.visible)
+    }
+
+    /// Composites the visible layers over the background.
+    pub fn flatten(&self) -> DrawResponse {
+        let mut im = background_image(self.width, self.height, self.background);
+        for layer in self.layers.iter().filter(|l| l.visible) {
+            merge_layer_with_opacity(&mut im, &layer.mask, &layer.paint, layer.opacity);
+        }
+        DrawResponse { im, sym: self.sym }
+    }
+}
+
 pub fn merge_one(img: &mut RgbImage, layer: &Array2<u8>, color: Rgb<u8>) {
     for (x, y, pix) in img.enumerate_pixels_mut() {
         let alpha = layer[(x as usize, y as usize)];
@@ -18,6 +208,90 @@ pub fn merge_one(img: &mut RgbImage, layer: &Array2<u8>, color: Rgb<u8>) {
     }
 }
 
+/// Composites `color` with opacity `a` over a pixel with unpremultiplied
+/// color `old`.  The blend is done with premultiplied colors.
+fn blend(old: [f32; 4], color: [f32; 3], a: f32) -> [f32; 4] {
+    let a_out = a + old[3] * (1. - a);
+    if a_out == 0. {
+        return [0.; 4];
+    }
+    let mut out = [0.; 4];
+    for i in 0..3 {
+        out[i] = (color[i] * a + old[i] * old[3] * (1. - a)) / a_out;
+    }
+    out[3] = a_out;
+    out
+}
+
+fn merge_pixels<T, F>(
+    width: u32,
+    height: u32,
+    layer: &Array2<T>,
+    paint: &Paint,
+    opacity: f32,
+    mut f: F,
+) where
+    T: Intensity,
+    F: FnMut(u32, u32, [f32; 3], f32),
+{
+    for y in 0..height {
+        for x in 0..width {
+            let alpha = layer[(x as usize, y as usize)].alpha();
+            f(x, y, paint.color(alpha), alpha * opacity);
+        }
+    }
+}
+
+pub fn background_image(width: u32, height: u32, background: Background) -> DynamicImage {
+    match background {
+        Background::Color(c) => {
+            Rgb32FImage::from_pixel(width, height, Rgb(c.0.map(|v| (v as f32) / 255.))).into()
+        }
+        Background::Transparent => Rgba32FImage::new(width, height).into(),
+    }
+}
+
+/// Composites a layer over an image.  Images created by `background_image`
+/// are blended in floating point, so no precision is lost until the image
+/// is quantized.
+pub fn merge_layer<T: Intensity>(img: &mut DynamicImage, layer: &Array2<T>, paint: &Paint) {
+    merge_layer_with_opacity(img, layer, paint, 1.)
+}
+
+/// Like `merge_layer`, but with the mask scaled by `opacity`.
+pub fn merge_layer_with_opacity<T: Intensity>(
+    img: &mut DynamicImage,
+    layer: &Array2<T>,
+    paint: &Paint,
+    opacity: f32,
+) {
+    let (w, h) = (img.width(), img.height());
+    match img {
+        DynamicImage::ImageRgb32F(im) => merge_pixels(w, h, layer, paint, opacity, |x, y, c, a| {
+            let p = im.get_pixel_mut(x, y);
+            let o = blend([p[0], p[1], p[2], 1.], c, a);
+            *p = Rgb([o[0], o[1], o[2]]);
+        }),
+        DynamicImage::ImageRgba32F(im) => {
+            merge_pixels(w, h, layer, paint, opacity, |x, y, c, a| {
+                let p = im.get_pixel_mut(x, y);
+                *p = Rgba(blend(p.0, c, a));
+            })
+        }
+        DynamicImage::ImageRgb8(im) => merge_pixels(w, h, layer, paint, opacity, |x, y, c, a| {
+            let p = im.get_pixel_mut(x, y);
+            let o = blend([p[0], p[1], p[2], 255].map(|v| (v as f32) / 255.), c, a);
+            *p = Rgb([o[0], o[1], o[2]].map(|v| (255. * v + 0.5) as u8));
+        }),
+        DynamicImage::ImageRgba8(im) => merge_pixels(w, h, layer, paint, opacity, |x, y, c, a| {
+            let p = im.get_pixel_mut(x, y);
+            let o = blend(p.0.map(|v| (v as f32) / 255.), c, a);
+            *p = Rgba(o.map(|v| (255. * v + 0.5) as u8));
+        }),
+        _ => panic!("unsupported image type"),
+    }
+}
+
 pub fn merge_random_color<'a>(img: &'a mut RgbImage) -> impl FnMut(&'a Array2<u8>) {
     move |layer| {
         merge_one(img, layer, Rgb(sample(random::Color)));
@@ -4,6 +4,7 @@ extern crate ndarray;
 extern crate num_complex;
 extern crate num_traits;
 extern crate ordered_float;
+extern crate png;
 extern crate rand;
 extern crate serde;
 #[macro_use]
@@ -15,21 +16,34 @@ extern crate rustfft;
 extern crate serde_json;
 extern crate strum;
 extern crate strum_macros;
+extern crate thiserror;
 
+pub mod animate;
 pub mod canvas;
+pub mod color;
+pub mod export;
 pub mod fft;
+pub mod field;
 pub mod layer;
+pub mod memory;
+pub mod pyramid;
+pub mod quantize;
 pub mod random;
 pub mod rng;
 pub mod schema;
+pub mod supersample;
+pub mod svg;
 pub mod symmetric_canvas;
 pub mod symmetry;
+pub mod warp;
 
-use image::RgbImage;
+use image::DynamicImage;
 #[cfg(feature = "threads")]
 use rayon::prelude::*;
 
+use crate::quantize::Dither;
 use crate::rng::sample;
+use crate::supersample::Supersampler;
 use crate::symmetry::SymmetryGroup;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -52,8 +66,65 @@ pub trait Design: serde::de::DeserializeOwned {
     fn name() -> &'static str;
     fn schema() -> serde_json::Value;
     fn draw(&self) -> Result<DrawResponse, Box<dyn std::error::Error>>;
+    /// The width and height of the image that `draw` returns, if they are
+    /// known without drawing it.
+    fn dimensions(&self) -> Option<(u32, u32)> {
+        None
+    }
+    /// An estimate of the peak number of bytes that `draw` allocates, taking
+    /// the budget set by `memory::set_budget` into account.  By default only
+    /// the output image is counted, as an opaque floating point image.
+    fn memory_estimate(&self) -> usize {
+        self.dimensions().map_or(0, |(width, height)| {
+            memory::image_bytes(width, height, false)
+        })
+    }
+}
+
+/// A design made by compositing layers, which can also be returned before
+/// they are merged, so that they can be restyled without being redrawn.
+pub trait LayeredDesign: Design {
+    fn draw_layers(&self) -> Result<layer::LayeredImage, Box<dyn std::error::Error>>;
+    /// An estimate of the peak number of bytes that `draw_layers`
+    /// allocates.  Every layer is kept, so this can be much more than
+    /// `memory_estimate`, which it is by default.
+    fn layers_memory_estimate(&self) -> usize {
+        self.memory_estimate()
+    }
+}
+
+/// A design that can evolve over time, rendered as a sequence of frames.
+pub trait Animate: Design {
+    /// Does the work shared by all frames, and returns a function that
+    /// draws each frame.  With the same seed, the frames show the same
+    /// design that `draw` returns, with the same random choices, but a
+    /// design may render them differently, so no frame need match `draw`
+    /// pixel for pixel.
+    fn animation(&self) -> Result<animate::Animation, Box<dyn std::error::Error>>;
+}
+
+/// A design that can be blended continuously into another set of
+/// parameters, or another seed, for the same design.
+pub trait Morph: Design {
+    /// Returns a function that draws the blend, which is `self` at time 0
+    /// and `other` at time 1.  The animation is not periodic, so it plays
+    /// forwards and backwards when it loops.
+    fn morph(&self, other: &Self) -> Result<animate::Animation, Box<dyn std::error::Error>>;
 }
 
+pub type PixelFn = Box<dyn Fn(f64, f64) -> [f64; 3] + Send + Sync>;
+
+/// A design where each pixel is a function of its coordinates alone, so
+/// that the image can be rendered in pieces rather than all at once.
+pub trait PixelDesign {
+    fn size(&self) -> (u32, u32);
+    fn sampler(&self) -> Supersampler;
+    /// The color at a point, with channels in `[0, 1]`.  Pixel `(x, y)` is
+    /// centered at the point `(x, y)`.
+    fn pixel_fn(&self) -> Result<PixelFn, Box<dyn std::error::Error>>;
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SymmetryType {
     Wrapped(SymmetryGroup),
     None,
@@ -66,28 +137,115 @@ impl From<SymmetryGroup> for SymmetryType {
 }
 
 pub struct DrawResponse {
-    pub im: RgbImage,
+    pub im: DynamicImage,
     pub sym: SymmetryType,
 }
 
-#[cfg(feature = "threads")]
+impl DrawResponse {
+    pub fn is_periodic(&self) -> bool {
+        matches!(self.sym, SymmetryType::Wrapped(_))
+    }
+
+    /// The image with 8 bits per channel.
+    pub fn quantize(&self, dither: Dither) -> DynamicImage {
+        quantize::quantize(&self.im, dither, self.is_periodic())
+    }
+}
+
+/// How much work a render does.  A preview has the same composition as the
+/// full render with the same seed, but is drawn at a lower resolution and
+/// with cheaper settings.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    strum_macros::Display,
+    strum_macros::EnumIter,
+    strum_macros::EnumString,
+)]
+pub enum Quality {
+    #[default]
+    Full,
+    Preview,
+}
+
+/// The largest dimension of a preview.
+pub const PREVIEW_SIZE: u32 = 256;
+
+impl Quality {
+    /// The factor by which an image of the given size is shrunk.
+    pub fn scale(self, width: u32, height: u32) -> f64 {
+        match self {
+            Quality::Full => 1.,
+            Quality::Preview => f64::min(1., (PREVIEW_SIZE as f64) / (width.max(height) as f64)),
+        }
+    }
+
+    pub fn is_preview(self) -> bool {
+        self == Quality::Preview
+    }
+}
+
+/// Settings that control how a `DrawResponse` is turned into an image file
+/// or displayed, as opposed to what is drawn.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct OutputOptions {
+    #[serde(default)]
+    pub dither: Dither,
+}
+
 pub fn make_layers_n<F, T: Send>(n: usize, f: F) -> impl Iterator<Item = T>
 where
     F: Fn(usize) -> T + Send + Sync,
 {
-    (0..n)
-        .into_par_iter()
-        .map(f)
-        .collect::<Vec<_>>()
-        .into_iter()
+    make_layers_n_sized(n, 0, 0, f)
+}
+
+/// Like `make_layers_n`, but generates only as many layers at a time as fit
+/// in the memory budget, given that each layer uses `layer_bytes` and the
+/// rest of the render uses `reserved`.
+///
+/// Each layer is generated with its own seed, derived from the generator of
+/// the calling thread, so the layers are reproducible under `rng::with_seed`
+/// however they are scheduled.
+#[cfg(feature = "threads")]
+pub fn make_layers_n_sized<F, T: Send>(
+    n: usize,
+    layer_bytes: usize,
+    reserved: usize,
+    f: F,
+) -> impl Iterator<Item = T>
+where
+    F: Fn(usize) -> T + Send + Sync,
+{
+    let k = memory::layers_in_flight(n, layer_bytes, reserved);
+    let seed = rng::next_seed();
+    let g = move |i: usize| rng::with_seed(rng::derive_seed(seed, i as u64), || f(i));
+    (0..n).step_by(k).flat_map(move |start| {
+        (start..(start + k).min(n))
+            .into_par_iter()
+            .map(&g)
+            .collect::<Vec<_>>()
+    })
 }
 
 #[cfg(not(feature = "threads"))]
-pub fn make_layers_n<F, T: Send>(n: usize, f: F) -> impl Iterator<Item = T>
+pub fn make_layers_n_sized<F, T: Send>(
+    n: usize,
+    _layer_bytes: usize,
+    _reserved: usize,
+    f: F,
+) -> impl Iterator<Item = T>
 where
     F: Fn(usize) -> T + Send + Sync,
 {
-    (0..n).map(f)
+    let seed = rng::next_seed();
+    (0..n).map(move |i| rng::with_seed(rng::derive_seed(seed, i as u64), || f(i)))
 }
 
 pub fn make_layers<F, T: Send>(n: usize, f: F) -> impl Iterator<Item = T>
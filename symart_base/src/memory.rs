@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static BUDGET: AtomicUsize = AtomicUsize::new(0);
+
+/// Limits the memory, in bytes, that a single render should use.  `None`
+/// removes the limit.  The budget is only a guide: it controls how many
+/// layers are generated at once, but at least one layer is always in flight.
+pub fn set_budget(bytes: Option<usize>) {
+    BUDGET.store(bytes.unwrap_or(0), Ordering::Relaxed);
+}
+
+pub fn budget() -> Option<usize> {
+    match BUDGET.load(Ordering::Relaxed) {
+        0 => None,
+        b => Some(b),
+    }
+}
+
+/// The number of layers, out of `n`, that `make_layers_n_sized` generates
+/// at the same time, given that each layer needs `layer_bytes` and that
+/// `reserved` bytes are used by the rest of the render.
+#[cfg(feature = "threads")]
+pub fn layers_in_flight(n: usize, layer_bytes: usize, reserved: usize) -> usize {
+    match budget() {
+        Some(b) if layer_bytes > 0 => (b.saturating_sub(reserved) / layer_bytes).clamp(1, n.max(1)),
+        _ => n.max(1),
+    }
+}
+
+#[cfg(not(feature = "threads"))]
+pub fn layers_in_flight(_n: usize, _layer_bytes: usize, _reserved: usize) -> usize {
+    1
+}
+
+/// An estimate of the peak memory used by a render that generates `n`
+/// layers with `make_layers_n_sized`.
+pub fn layered_estimate(n: usize, layer_bytes: usize, reserved: usize) -> usize {
+    let in_flight = layers_in_flight(n, layer_bytes, reserved).min(n);
+    reserved + in_flight * layer_bytes
+}
+
+/// Like `layered_estimate`, for a render that keeps the layers rather than
+/// merging them into an image, in `kept` bytes in all.
+pub fn kept_layers_estimate(n: usize, layer_bytes: usize, reserved: usize, kept: usize) -> usize {
+    let in_flight = layers_in_flight(n, layer_bytes, reserved).min(n);
+    kept + in_flight * layer_bytes
+}
+
+/// The number of bytes used by a floating point image.
+pub fn image_bytes(width: u32, height: u32, alpha: bool) -> usize {
+    let channels = if alpha { 4 } else { 3 };
+    (width as usize) * (height as usize) * channels * std::mem::size_of::<f32>()
+}
+
+const DEFAULT_BAND_ROWS: u32 = 64;
+
+/// The number of rows to render at once when an image is streamed, given
+/// the bytes needed for each row.
+pub fn band_rows(row_bytes: usize, height: u32) -> u32 {
+    let rows = match budget() {
+        Some(b) => (b / row_bytes.max(1)).min(u32::MAX as usize) as u32,
+        None => DEFAULT_BAND_ROWS,
+    };
+    rows.clamp(1, height.max(1))
+}
@@ -0,0 +1,251 @@
+use image::{DynamicImage, Rgb32FImage};
+#[cfg(feature = "threads")]
+use rayon::prelude::*;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use strum_macros::{Display, EnumIter, EnumString};
+
+use crate::export::{export_image, BitDepth, ExportError, ExportOptions};
+use crate::supersample::Supersampler;
+use crate::{DrawResponse, PixelDesign, PixelFn};
+
+/// The most samples per axis used for a pixel of a reduced level.  Reduced
+/// levels cover many pixels of the full image, so they need more samples
+/// than the full image to avoid aliasing.
+const MAX_LEVEL_SAMPLES: u32 = 4;
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum Descriptor {
+    /// A `.dzi` XML file.
+    #[default]
+    Xml,
+    /// The same information as JSON, as read by OpenSeadragon.
+    Json,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PyramidOptions {
+    #[serde(default = "default_tile_size")]
+    pub tile_size: u32,
+    /// The number of pixels that each tile shares with its neighbors.
+    #[serde(default = "default_overlap")]
+    pub overlap: u32,
+    #[serde(default)]
+    pub descriptor: Descriptor,
+    /// How tiles are encoded.  The bit depth must be 8, since viewers
+    /// display tiles directly.
+    #[serde(flatten)]
+    pub export: ExportOptions,
+}
+
+fn default_tile_size() -> u32 {
+    256
+}
+
+fn default_overlap() -> u32 {
+    1
+}
+
+impl Default for PyramidOptions {
+    fn default() -> Self {
+        Self {
+            tile_size: default_tile_size(),
+            overlap: default_overlap(),
+            descriptor: Descriptor::default(),
+            export: ExportOptions::default(),
+        }
+    }
+}
+
+/// A periodic design repeated to fill a larger image.
+pub struct Wallpaper {
+    tile: Rgb32FImage,
+    pub width: u32,
+    pub height: u32,
+    pub sampler: Supersampler,
+}
+
+impl Wallpaper {
+    /// Alpha is dropped, so transparent backgrounds come out black.
+    pub fn new(tile: &DynamicImage, width: u32, height: u32) -> Self {
+        Self {
+            tile: tile.to_rgb32f(),
+            width,
+            height,
+            sampler: Supersampler::default(),
+        }
+    }
+
+    pub fn from_response(response: &DrawResponse, width: u32, height: u32) -> Self {
+        Self::new(&response.im, width, height)
+    }
+}
+
+impl PixelDesign for Wallpaper {
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    fn sampler(&self) -> Supersampler {
+        self.sampler
+    }
+
+    fn pixel_fn(&self) -> Result<PixelFn, Box<dyn std::error::Error>> {
+        let tile = self.tile.clone();
+        let (tw, th) = (tile.width() as i64, tile.height() as i64);
+        Ok(Box::new(move |x, y| {
+            let tx = (x.round() as i64).rem_euclid(tw) as u32;
+            let ty = (y.round() as i64).rem_euclid(th) as u32;
+            tile.get_pixel(tx, ty).0.map(|v| v as f64)
+        }))
+    }
+}
+
+/// The index of the full resolution level.  Level 0 is a single pixel.
+pub fn max_level(width: u32, height: u32) -> u32 {
+    let m = width.max(height).max(1);
+    32 - (m - 1).leading_zeros()
+}
+
+/// The size of the image at a level.
+pub fn level_size(width: u32, height: u32, level: u32) -> (u32, u32) {
+    let shift = max_level(width, height) - level;
+    let reduce = |n: u32| (((n as u64) + (1 << shift) - 1) >> shift).max(1) as u32;
+    (reduce(width), reduce(height))
+}
+
+fn descriptor(width: u32, height: u32, opts: &PyramidOptions) -> String {
+    let format = opts.export.format.extension();
+    match opts.descriptor {
+        Descriptor::Xml => format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" ",
+                "Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n",
+                "  <Size Width=\"{}\" Height=\"{}\"/>\n",
+                "</Image>\n"
+            ),
+            format, opts.overlap, opts.tile_size, width, height
+        ),
+        Descriptor::Json => serde_json::to_string_pretty(&serde_json::json!({
+            "Image": {
+                "xmlns": "http://schemas.microsoft.com/deepzoom/2008",
+                "Format": format,
+                "Overlap": opts.overlap.to_string(),
+                "TileSize": opts.tile_size.to_string(),
+                "Size": {
+                    "Width": width.to_string(),
+                    "Height": height.to_string()
+                }
+            }
+        }))
+        .unwrap(),
+    }
+}
+
+struct Tile {
+    col: u32,
+    row: u32,
+}
+
+fn render_tile(
+    f: &PixelFn,
+    sampler: &Supersampler,
+    scale: f64,
+    level_size: (u32, u32),
+    tile: &Tile,
+    opts: &PyramidOptions,
+    path: &Path,
+) -> Result<(), ExportError> {
+    let (t, o) = (opts.tile_size, opts.overlap);
+    let x0 = (tile.col * t).saturating_sub(o);
+    let y0 = (tile.row * t).saturating_sub(o);
+    let x1 = ((tile.col + 1) * t + o).min(level_size.0);
+    let y1 = ((tile.row + 1) * t + o).min(level_size.1);
+    let center = 0.5 * (scale - 1.);
+    let g = |x: f64, y: f64| f(scale * x + center, scale * y + center);
+    let data = sampler.render_region(x0, y0, x1 - x0, y1 - y0, &g);
+    let im: DynamicImage = Rgb32FImage::from_raw(x1 - x0, y1 - y0, data)
+        .unwrap()
+        .into();
+    let mut w = BufWriter::new(File::create(path)?);
+    export_image(&im, false, &opts.export, &mut w)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// Writes a Deep Zoom image: the descriptor `name.dzi` (or `name.json`)
+/// and the tiles `name_files/<level>/<col>_<row>.<ext>`, in `dir`.  Each
+/// tile is rendered on its own, so the full image is never held in memory.
+pub fn export_dzi<D: PixelDesign>(
+    design: &D,
+    opts: &PyramidOptions,
+    dir: &Path,
+    name: &str,
+) -> Result<(), ExportError> {
+    if opts.export.bit_depth != BitDepth::Eight {
+        return Err(ExportError::BitDepth(
+            opts.export.format,
+            opts.export.bit_depth.into(),
+        ));
+    }
+    let (width, height) = design.size();
+    let f = design
+        .pixel_fn()
+        .map_err(|e| ExportError::Design(e.to_string()))?;
+    let base = design.sampler();
+    let ext = match opts.descriptor {
+        Descriptor::Xml => "dzi",
+        Descriptor::Json => "json",
+    };
+    fs::create_dir_all(dir)?;
+    fs::write(
+        dir.join(format!("{}.{}", name, ext)),
+        descriptor(width, height, opts),
+    )?;
+    let top = max_level(width, height);
+    for level in 0..=top {
+        let level_dir = dir.join(format!("{}_files", name)).join(level.to_string());
+        fs::create_dir_all(&level_dir)?;
+        let size = level_size(width, height, level);
+        let scale = (1u64 << (top - level)) as f64;
+        let sampler = Supersampler {
+            samples: base.samples.max((scale as u32).min(MAX_LEVEL_SAMPLES)),
+            ..base
+        };
+        let (cols, rows) = (
+            size.0.div_ceil(opts.tile_size),
+            size.1.div_ceil(opts.tile_size),
+        );
+        let tiles: Vec<Tile> = (0..rows)
+            .flat_map(|row| (0..cols).map(move |col| Tile { col, row }))
+            .collect();
+        let write = |tile: &Tile| {
+            let path = level_dir.join(format!(
+                "{}_{}.{}",
+                tile.col,
+                tile.row,
+                opts.export.format.extension()
+            ));
+            render_tile(&f, &sampler, scale, size, tile, opts, &path)
+        };
+        #[cfg(feature = "threads")]
+        tiles.par_iter().try_for_each(write)?;
+        #[cfg(not(feature = "threads"))]
+        tiles.iter().try_for_each(write)?;
+    }
+    Ok(())
+}
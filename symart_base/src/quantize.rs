@@ -0,0 +1,360 @@
+use image::{DynamicImage, ImageBuffer};
+use ndarray::Array2;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, StandardNormal};
+use strum_macros::{Display, EnumIter, EnumString};
+
+use crate::fft::Plan2D;
+
+/// How floating point images are rounded to integer channels.  For images
+/// that tile, the dither pattern repeats with the tile, so there is no seam
+/// where copies of the image meet.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum Dither {
+    #[default]
+    None,
+    Bayer,
+    BlueNoise,
+    ErrorDiffusion,
+}
+
+const BAYER_MAX: u32 = 16;
+const BLUE_NOISE_MAX: u32 = 128;
+const BLUE_NOISE_FULL_MAX: u32 = 2048;
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn bayer_index(x: u32, y: u32, bits: u32) -> u32 {
+    let mut v = 0;
+    for i in 0..bits {
+        let bx = (x >> i) & 1;
+        let by = (y >> i) & 1;
+        v = (v << 2) | ((bx ^ by) << 1) | by;
+    }
+    v
+}
+
+/// The size of the Bayer matrix.  For periodic images this is the largest
+/// power of two dividing both dimensions, so that the matrix tiles the image.
+fn bayer_size(width: u32, height: u32, periodic: bool) -> u32 {
+    if periodic {
+        let g = gcd(width, height);
+        (g & g.wrapping_neg()).min(BAYER_MAX)
+    } else {
+        BAYER_MAX
+    }
+}
+
+/// The period of the blue noise pattern along an axis of length `n`: the
+/// largest divisor of `n` up to `BLUE_NOISE_MAX`, or `n` itself if that is
+/// small, so that the pattern tiles the image.
+fn blue_noise_period(n: u32) -> u32 {
+    let d = (1..=BLUE_NOISE_MAX.min(n))
+        .rev()
+        .find(|d| n.is_multiple_of(*d))
+        .unwrap();
+    if d >= 16 {
+        d
+    } else {
+        n
+    }
+}
+
+/// Whether the blue noise tile of a periodic image is small enough to make.
+/// If not, periodic error diffusion is used instead.
+fn blue_noise_fits(width: u32, height: u32) -> bool {
+    let (tw, th) = (blue_noise_period(width), blue_noise_period(height));
+    (tw as u64) * (th as u64) <= (BLUE_NOISE_FULL_MAX as u64).pow(2)
+}
+
+/// Thresholds in `[0, 1)` with a blue noise spectrum, made by high pass
+/// filtering white noise on the torus and then ranking the values.
+pub fn blue_noise(width: u32, height: u32) -> Array2<f32> {
+    let (w, h) = (width as usize, height as usize);
+    let mut rng = ChaCha8Rng::seed_from_u64(0x5eed);
+    let noise = Array2::from_shape_fn((w, h), |_| StandardNormal.sample(&mut rng));
+    let plan = Plan2D::<f64>::cached(w, h);
+    let mut spectrum = plan.forward_real(noise);
+    let ax = 2. * std::f64::consts::PI / (w as f64);
+    let ay = 2. * std::f64::consts::PI / (h as f64);
+    for ((x, y), d) in spectrum.indexed_iter_mut() {
+        *d *= 2. - f64::cos(ax * (x as f64)) - f64::cos(ay * (y as f64));
+    }
+    let arr = plan.inverse_real(spectrum);
+    let mut order: Vec<usize> = (0..arr.len()).collect();
+    let flat = arr.as_slice().unwrap();
+    order.sort_by(|&i, &j| flat[i].total_cmp(&flat[j]));
+    let mut out = Array2::zeros((w, h));
+    let n = order.len() as f32;
+    let slice = out.as_slice_mut().unwrap();
+    for (rank, &i) in order.iter().enumerate() {
+        slice[i] = ((rank as f32) + 0.5) / n;
+    }
+    out
+}
+
+fn threshold_fn(
+    dither: Dither,
+    width: u32,
+    height: u32,
+    periodic: bool,
+) -> Box<dyn Fn(u32, u32) -> f32> {
+    match dither {
+        Dither::Bayer => {
+            let n = bayer_size(width, height, periodic);
+            let bits = n.trailing_zeros();
+            let scale = (n * n) as f32;
+            Box::new(move |x, y| ((bayer_index(x % n, y % n, bits) as f32) + 0.5) / scale)
+        }
+        Dither::BlueNoise => {
+            let (tw, th) = if periodic {
+                (blue_noise_period(width), blue_noise_period(height))
+            } else {
+                (64, 64)
+            };
+            let noise = blue_noise(tw, th);
+            Box::new(move |x, y| noise[((x % tw) as usize, (y % th) as usize)])
+        }
+        _ => Box::new(|_, _| 0.5),
+    }
+}
+
+/// Floyd-Steinberg error diffusion.  For periodic images the errors that
+/// wrap around to pixels that were already visited are carried into a
+/// second pass, so the first row and column see the same error as any other.
+fn error_diffusion(
+    data: &[f32],
+    width: usize,
+    height: usize,
+    channels: usize,
+    max: f32,
+    periodic: bool,
+) -> Vec<u16> {
+    let mut out = vec![0; data.len()];
+    let idx = |x: usize, y: usize, c: usize| (y * width + x) * channels + c;
+    let mut err = vec![0f32; data.len()];
+    let passes = if periodic { 2 } else { 1 };
+    for _ in 0..passes {
+        let mut carry = vec![0f32; data.len()];
+        for y in 0..height {
+            for x in 0..width {
+                for c in 0..channels {
+                    let i = idx(x, y, c);
+                    let v = data[i].clamp(0., 1.) * max + err[i];
+                    let q = v.round().clamp(0., max);
+                    out[i] = q as u16;
+                    let e = v - q;
+                    let targets: [(isize, isize, f32); 4] =
+                        [(1, 0, 7.), (-1, 1, 3.), (0, 1, 5.), (1, 1, 1.)];
+                    for (dx, dy, wt) in targets {
+                        let tx = (x as isize) + dx;
+                        let ty = (y as isize) + dy;
+                        let wraps_x = tx < 0 || tx >= width as isize;
+                        let wraps_y = ty >= height as isize;
+                        if !periodic && (wraps_x || wraps_y) {
+                            continue;
+                        }
+                        let tx = tx.rem_euclid(width as isize) as usize;
+                        let ty = ty.rem_euclid(height as isize) as usize;
+                        let visited = wraps_y || (dy == 0 && wraps_x);
+                        let buf = if visited { &mut carry } else { &mut err };
+                        buf[idx(tx, ty, c)] += e * wt / 16.;
+                    }
+                }
+            }
+        }
+        err = carry;
+    }
+    out
+}
+
+fn quantize_channels(
+    data: &[f32],
+    width: u32,
+    height: u32,
+    channels: usize,
+    max: f32,
+    dither: Dither,
+    periodic: bool,
+) -> Vec<u16> {
+    let diffuse = match dither {
+        Dither::ErrorDiffusion => true,
+        Dither::BlueNoise => periodic && !blue_noise_fits(width, height),
+        _ => false,
+    };
+    if diffuse {
+        return error_diffusion(
+            data,
+            width as usize,
+            height as usize,
+            channels,
+            max,
+            periodic,
+        );
+    }
+    let threshold = threshold_fn(dither, width, height, periodic);
+    let mut out = Vec::with_capacity(data.len());
+    for y in 0..height {
+        for x in 0..width {
+            let t = threshold(x, y);
+            let base = ((y * width + x) as usize) * channels;
+            for v in &data[base..(base + channels)] {
+                out.push((v.clamp(0., 1.) * max + t).floor().min(max) as u16);
+            }
+        }
+    }
+    out
+}
+
+fn float_data(img: &DynamicImage) -> (Vec<f32>, usize) {
+    match (img.color().has_color(), img.color().has_alpha()) {
+        (true, true) => (img.to_rgba32f().into_raw(), 4),
+        (true, false) => (img.to_rgb32f().into_raw(), 3),
+        (false, true) => {
+            let im = img.to_rgba32f();
+            (im.pixels().flat_map(|p| [p[0], p[3]]).collect(), 2)
+        }
+        (false, false) => {
+            let im = img.to_rgb32f();
+            (im.pixels().map(|p| p[0]).collect(), 1)
+        }
+    }
+}
+
+fn to_image8(width: u32, height: u32, channels: usize, data: Vec<u16>) -> DynamicImage {
+    let data: Vec<u8> = data.into_iter().map(|v| v as u8).collect();
+    match channels {
+        1 => DynamicImage::ImageLuma8(ImageBuffer::from_raw(width, height, data).unwrap()),
+        2 => DynamicImage::ImageLumaA8(ImageBuffer::from_raw(width, height, data).unwrap()),
+        3 => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, data).unwrap()),
+        _ => DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, data).unwrap()),
+    }
+}
+
+fn to_image16(width: u32, height: u32, channels: usize, data: Vec<u16>) -> DynamicImage {
+    match channels {
+        1 => DynamicImage::ImageLuma16(ImageBuffer::from_raw(width, height, data).unwrap()),
+        2 => DynamicImage::ImageLumaA16(ImageBuffer::from_raw(width, height, data).unwrap()),
+        3 => DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, data).unwrap()),
+        _ => DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, data).unwrap()),
+    }
+}
+
+/// Converts an image to 8 bits per channel.  `periodic` should be set if
+/// the image is a tile of a periodic pattern.
+pub fn quantize(img: &DynamicImage, dither: Dither, periodic: bool) -> DynamicImage {
+    let (w, h) = (img.width(), img.height());
+    let (data, channels) = float_data(img);
+    let q = quantize_channels(&data, w, h, channels, 255., dither, periodic);
+    to_image8(w, h, channels, q)
+}
+
+/// Like `quantize`, but with 16 bits per channel.
+pub fn quantize16(img: &DynamicImage, dither: Dither, periodic: bool) -> DynamicImage {
+    let (w, h) = (img.width(), img.height());
+    let (data, channels) = float_data(img);
+    let q = quantize_channels(&data, w, h, channels, 65535., dither, periodic);
+    to_image16(w, h, channels, q)
+}
+
+/// Quantizes an image one row at a time, top to bottom, for images that are
+/// too large to hold in memory.  The results are the same as `quantize` on
+/// the whole image with `periodic` unset.
+pub struct RowQuantizer {
+    width: usize,
+    channels: usize,
+    max: f32,
+    dither: Dither,
+    threshold: Box<dyn Fn(u32, u32) -> f32>,
+    err: Vec<f32>,
+    y: u32,
+}
+
+impl RowQuantizer {
+    /// `max` is the largest output value, 255 or 65535.
+    pub fn new(width: u32, channels: usize, max: f32, dither: Dither) -> Self {
+        let len = (width as usize) * channels;
+        Self {
+            width: width as usize,
+            channels,
+            max,
+            dither,
+            threshold: threshold_fn(dither, width, 0, false),
+            err: vec![0.; len],
+            y: 0,
+        }
+    }
+
+    /// Quantizes the next row, given as packed channel values.
+    pub fn quantize_row(&mut self, row: &[f32]) -> Vec<u16> {
+        let (w, ch, max) = (self.width, self.channels, self.max);
+        let mut out = Vec::with_capacity(row.len());
+        if self.dither == Dither::ErrorDiffusion {
+            let mut next = vec![0f32; row.len()];
+            for x in 0..w {
+                for c in 0..ch {
+                    let i = x * ch + c;
+                    let v = row[i].clamp(0., 1.) * max + self.err[i];
+                    let q = v.round().clamp(0., max);
+                    out.push(q as u16);
+                    let e = v - q;
+                    if x + 1 < w {
+                        self.err[i + ch] += e * 7. / 16.;
+                        next[i + ch] += e / 16.;
+                    }
+                    if x > 0 {
+                        next[i - ch] += e * 3. / 16.;
+                    }
+                    next[i] += e * 5. / 16.;
+                }
+            }
+            self.err = next;
+        } else {
+            for (x, px) in row.chunks_exact(ch).enumerate() {
+                let t = (self.threshold)(x as u32, self.y);
+                for v in px {
+                    out.push((v.clamp(0., 1.) * max + t).floor().min(max) as u16);
+                }
+            }
+        }
+        self.y += 1;
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blue_noise_wraps() {
+        // A prime width too large for a full tile along both axes.
+        let (w, h) = (2053, 48);
+        let t = threshold_fn(Dither::BlueNoise, w, h, true);
+        for y in 0..h {
+            for x in 0..w {
+                assert_eq!(t(x, y), t(x + w, y));
+                assert_eq!(t(x, y), t(x, y + h));
+            }
+        }
+    }
+}
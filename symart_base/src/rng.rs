@@ -1,19 +1,59 @@
 use rand::distributions::Distribution;
 use rand::rngs::{OsRng, SmallRng};
-use rand::SeedableRng;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use std::cell::RefCell;
 
-fn make_rng() -> SmallRng {
-    SmallRng::from_rng(OsRng).unwrap()
+/// The generator of a thread.  Unseeded renders use the fast `SmallRng`.
+/// Seeded ones use `ChaCha8Rng`, whose output is specified by name rather
+/// than left to the version of rand, and is the same on every platform,
+/// including wasm, so a saved seed keeps reproducing its render.
+pub enum DesignRng {
+    Fresh(SmallRng),
+    Seeded(Box<ChaCha8Rng>),
+}
+
+impl RngCore for DesignRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            DesignRng::Fresh(r) => r.next_u32(),
+            DesignRng::Seeded(r) => r.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            DesignRng::Fresh(r) => r.next_u64(),
+            DesignRng::Seeded(r) => r.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            DesignRng::Fresh(r) => r.fill_bytes(dest),
+            DesignRng::Seeded(r) => r.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        match self {
+            DesignRng::Fresh(r) => r.try_fill_bytes(dest),
+            DesignRng::Seeded(r) => r.try_fill_bytes(dest),
+        }
+    }
+}
+
+fn make_rng() -> DesignRng {
+    DesignRng::Fresh(SmallRng::from_rng(OsRng).unwrap())
 }
 
 thread_local! {
-    static RNG: RefCell<SmallRng> = RefCell::new(make_rng());
+    static RNG: RefCell<DesignRng> = RefCell::new(make_rng());
 }
 
 pub fn sample_fn<F, T>(f: F) -> T
 where
-    F: FnOnce(&mut SmallRng) -> T,
+    F: FnOnce(&mut DesignRng) -> T,
 {
     RNG.with(|r| f(&mut r.borrow_mut()))
 }
@@ -24,3 +64,46 @@ where
 {
     sample_fn(|r| dist.sample(r))
 }
+
+/// Runs `f` with the generator of the current thread seeded with `seed`, and
+/// restores the generator afterwards.
+pub fn with_seed<F, T>(seed: u64, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let old = RNG.with(|r| r.replace(DesignRng::Seeded(Box::new(seeded_rng(seed)))));
+    let out = f();
+    RNG.with(|r| r.replace(old));
+    out
+}
+
+/// A generator with the sequence that `with_seed` gives the current thread,
+/// for code that draws from several seeded sequences in turn.
+pub fn seeded_rng(seed: u64) -> ChaCha8Rng {
+    ChaCha8Rng::seed_from_u64(seed)
+}
+
+/// Like `with_seed`, but leaves the generator alone if there is no seed.
+pub fn with_optional_seed<F, T>(seed: Option<u64>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    match seed {
+        Some(s) => with_seed(s, f),
+        None => f(),
+    }
+}
+
+/// Derives the seed of the `i`th of several independent tasks, so that the
+/// results do not depend on which thread runs each task.
+pub fn derive_seed(seed: u64, i: u64) -> u64 {
+    let mut z = seed ^ i.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A seed drawn from the generator of the current thread.
+pub fn next_seed() -> u64 {
+    sample_fn(|r| r.gen())
+}
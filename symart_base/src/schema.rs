@@ -1,6 +1,11 @@
 use serde_json::{json, Value};
 use strum::IntoEnumIterator;
 
+use crate::color::Colormap;
+use crate::fft::Precision;
+use crate::layer::Shading;
+use crate::quantize::Dither;
+use crate::supersample::{PixelFilter, SamplePattern};
 use crate::symmetry::SymmetryGroup;
 
 pub trait JsonSchema {
@@ -77,9 +82,233 @@ pub fn num_colors() -> Value {
     })
 }
 
+pub fn background() -> Value {
+    json!({
+        "type": "string",
+        "title": "Background",
+        "pattern": "^(transparent|#[0-9a-fA-F]{6})$",
+        "default": "#000000"
+    })
+}
+
+pub fn palette() -> Value {
+    json!({
+        "type": "array",
+        "title": "Palette",
+        "items": {
+            "type": "string",
+            "pattern": "^#[0-9a-fA-F]{6}$"
+        },
+        "default": []
+    })
+}
+
+pub fn colormap() -> Value {
+    json!({
+        "type": "string",
+        "title": "Colormap",
+        "enum": enum_strings::<Colormap>(),
+        "default": "Grayscale"
+    })
+}
+
+pub fn samples() -> Value {
+    json!({
+        "type": "integer",
+        "title": "Samples per pixel",
+        "enum": [1, 4, 9, 16, 25, 36, 64],
+        "default": 1
+    })
+}
+
+pub fn sample_pattern() -> Value {
+    json!({
+        "type": "string",
+        "title": "Sample pattern",
+        "enum": enum_strings::<SamplePattern>(),
+        "default": "Stratified"
+    })
+}
+
+pub fn pixel_filter() -> Value {
+    json!({
+        "type": "string",
+        "title": "Pixel filter",
+        "enum": enum_strings::<PixelFilter>(),
+        "default": "Box"
+    })
+}
+
+pub fn dither() -> Value {
+    json!({
+        "type": "string",
+        "title": "Dithering",
+        "enum": enum_strings::<Dither>(),
+        "default": "None"
+    })
+}
+
+/// Adds the properties of `OutputOptions` to a design's schema.
+pub fn add_output_options(v: &mut Value) {
+    v["properties"]["dither"] = dither();
+}
+
+/// A seed for the random number generator.  Omitting it gives a different
+/// image each time.
+pub fn seed() -> Value {
+    json!({
+        "type": "integer",
+        "title": "Seed",
+        "minimum": 0,
+        "maximum": 9007199254740991u64
+    })
+}
+
+pub fn quality() -> Value {
+    json!({
+        "type": "string",
+        "title": "Quality",
+        "enum": enum_strings::<crate::Quality>(),
+        "default": "Full"
+    })
+}
+
+/// The schema of `field::SpectralFilter`.
+pub fn spectral_filter() -> Value {
+    let number = |title: &str, default: f64| {
+        json!({
+            "type": "number",
+            "title": title,
+            "minimum": 0,
+            "default": default
+        })
+    };
+    let variant = |name: &str, title: &str, props: Value| {
+        let mut properties = json!({ "type": { "const": name } });
+        for (k, v) in props.as_object().unwrap() {
+            properties[k] = v.clone();
+        }
+        json!({
+            "title": title,
+            "type": "object",
+            "properties": properties,
+            "required": ["type"]
+        })
+    };
+    json!({
+        "title": "Filter",
+        "oneOf": [
+            variant("PowerLaw", "Power law", json!({})),
+            variant("BandPass", "Band pass", json!({
+                "frequency": {
+                    "type": "number",
+                    "title": "Frequency",
+                    "minimum": 0,
+                    "default": 8.
+                },
+                "width": {
+                    "type": "number",
+                    "title": "Width",
+                    "exclusiveMinimum": 0,
+                    "default": 2.
+                }
+            })),
+            variant("Ring", "Ring", json!({
+                "inner": number("Inner frequency", 6.),
+                "outer": number("Outer frequency", 10.)
+            })),
+            variant("Anisotropic", "Anisotropic", json!({
+                "angle": { "type": "number", "title": "Angle", "default": 0 },
+                "aspect": number("Aspect ratio", 4.)
+            })),
+            variant("Radial", "Radial table", json!({
+                "table": {
+                    "type": "array",
+                    "title": "Amplitudes",
+                    "items": { "type": "number", "minimum": 0 },
+                    "default": [1, 0]
+                }
+            }))
+        ],
+        "default": { "type": "PowerLaw" }
+    })
+}
+
+/// The schema of `field::Octaves`.
+pub fn octaves() -> Value {
+    json!({
+        "title": "Octaves",
+        "type": "object",
+        "properties": {
+            "count": {
+                "type": "integer",
+                "title": "Number of octaves",
+                "minimum": 1,
+                "maximum": 12,
+                "default": 1
+            },
+            "gain": {
+                "type": "number",
+                "title": "Gain",
+                "minimum": 0,
+                "default": 0.5
+            },
+            "exponents": {
+                "type": "array",
+                "title": "Exponent of each octave",
+                "items": { "type": "number" },
+                "default": []
+            }
+        },
+        "required": ["count", "gain"],
+        "default": { "count": 1, "gain": 0.5 }
+    })
+}
+
+/// The schema of `warp::WarpParams`.
+pub fn warp() -> Value {
+    json!({
+        "title": "Warp",
+        "type": "object",
+        "properties": {
+            "filter": spectral_filter(),
+            "exponent": {
+                "type": "number",
+                "title": "Exponent",
+                "default": 4
+            },
+            "amount": {
+                "type": "number",
+                "title": "Amount",
+                "minimum": 0,
+                "default": 0.02
+            }
+        },
+        "required": ["exponent", "amount"]
+    })
+}
+
+pub fn shading() -> Value {
+    json!({
+        "type": "string",
+        "title": "Shading",
+        "enum": enum_strings::<Shading>(),
+        "default": "Flat"
+    })
+}
+
+pub fn precision() -> Value {
+    json!({
+        "type": "string",
+        "title": "Precision",
+        "enum": enum_strings::<Precision>(),
+        "default": "Double"
+    })
+}
+
 pub fn enum_strings<T>() -> Vec<String>
 where
-    T: IntoEnumIterator + ?Sized,
+    T: IntoEnumIterator,
     T::Iterator: Iterator,
     <T::Iterator as Iterator>::Item: std::fmt::Display,
 {
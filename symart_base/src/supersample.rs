@@ -0,0 +1,209 @@
+use image::Rgb32FImage;
+#[cfg(feature = "threads")]
+use rayon::prelude::*;
+use strum_macros::{Display, EnumIter, EnumString};
+
+/// Where the samples are placed within the support of the filter.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum SamplePattern {
+    /// The centers of a regular grid.
+    Grid,
+    /// One random point in each cell of a regular grid, also known as a
+    /// jittered grid.
+    #[default]
+    Stratified,
+    /// Independent uniformly random points, with no grid.
+    Random,
+}
+
+/// The reconstruction filter used to weight the samples of a pixel.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum PixelFilter {
+    #[default]
+    Box,
+    Tent,
+    Gaussian,
+}
+
+impl PixelFilter {
+    /// Half the width of the filter's support, in pixels.
+    pub fn radius(self) -> f64 {
+        match self {
+            PixelFilter::Box => 0.5,
+            PixelFilter::Tent => 1.0,
+            PixelFilter::Gaussian => 1.5,
+        }
+    }
+
+    pub fn weight(self, d: f64) -> f64 {
+        match self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent => 1.0 - d.abs(),
+            PixelFilter::Gaussian => (-2.0 * d * d).exp(),
+        }
+    }
+}
+
+/// A hash of the pixel and sample index, so that the sample positions do
+/// not depend on the order in which pixels are rendered.
+fn hash(x: u32, y: u32, i: u32) -> u64 {
+    let mut z = ((x as u64) << 32 | (y as u64)) ^ ((i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn unit(h: u64) -> f64 {
+    ((h >> 11) as f64) / ((1u64 << 53) as f64)
+}
+
+/// Renders images from functions of continuous coordinates.  Pixel `(x, y)`
+/// is centered at the point `(x, y)`.
+#[derive(Clone, Copy, Debug)]
+pub struct Supersampler {
+    /// Samples per axis; each pixel gets `samples * samples` samples.
+    pub samples: u32,
+    pub pattern: SamplePattern,
+    pub filter: PixelFilter,
+}
+
+impl Default for Supersampler {
+    fn default() -> Self {
+        Self {
+            samples: 1,
+            pattern: SamplePattern::default(),
+            filter: PixelFilter::default(),
+        }
+    }
+}
+
+/// The number of samples per axis for a count of samples per pixel, if the
+/// count is a nonzero square.
+pub fn samples_per_axis(samples_per_pixel: u32) -> Option<u32> {
+    let n = (samples_per_pixel as f64).sqrt().round() as u32;
+    (n > 0 && n * n == samples_per_pixel).then_some(n)
+}
+
+impl Supersampler {
+    /// `samples` is the number of samples per axis.
+    pub fn new(samples: u32, pattern: SamplePattern, filter: PixelFilter) -> Self {
+        Self {
+            samples: samples.max(1),
+            pattern,
+            filter,
+        }
+    }
+
+    fn offset(&self, x: u32, y: u32, i: u32, j: u32) -> (f64, f64) {
+        let n = self.samples as f64;
+        let (u, v) = match self.pattern {
+            SamplePattern::Grid => (0.5, 0.5),
+            SamplePattern::Stratified => {
+                let h = hash(x, y, i * self.samples + j);
+                (unit(h), unit(h.rotate_left(32)))
+            }
+            SamplePattern::Random => {
+                let h = hash(x, y, i * self.samples + j);
+                return (
+                    self.filter.radius() * (2. * unit(h) - 1.),
+                    self.filter.radius() * (2. * unit(h.rotate_left(32)) - 1.),
+                );
+            }
+        };
+        let r = self.filter.radius();
+        (
+            r * (2. * ((i as f64) + u) / n - 1.),
+            r * (2. * ((j as f64) + v) / n - 1.),
+        )
+    }
+
+    pub fn pixel<F>(&self, x: u32, y: u32, f: &F) -> [f64; 3]
+    where
+        F: Fn(f64, f64) -> [f64; 3],
+    {
+        if self.samples <= 1 {
+            return f(x as f64, y as f64);
+        }
+        let mut tot = [0.; 3];
+        let mut wtot = 0.;
+        for i in 0..self.samples {
+            for j in 0..self.samples {
+                let (dx, dy) = self.offset(x, y, i, j);
+                let w = self.filter.weight(dx) * self.filter.weight(dy);
+                let c = f((x as f64) + dx, (y as f64) + dy);
+                for k in 0..3 {
+                    tot[k] += w * c[k];
+                }
+                wtot += w;
+            }
+        }
+        tot.map(|v| v / wtot)
+    }
+
+    /// A drop in replacement for `RgbImage::from_fn`.
+    pub fn render<F>(&self, width: u32, height: u32, f: F) -> Rgb32FImage
+    where
+        F: Fn(f64, f64) -> [f64; 3] + Sync,
+    {
+        let data = self.render_band(0, height, width, &f);
+        Rgb32FImage::from_raw(width, height, data).unwrap()
+    }
+
+    /// Renders the rows `y0..y1` as packed RGB values, without allocating
+    /// anything else per row.
+    pub fn render_band<F>(&self, y0: u32, y1: u32, width: u32, f: &F) -> Vec<f32>
+    where
+        F: Fn(f64, f64) -> [f64; 3] + Sync,
+    {
+        self.render_region(0, y0, width, y1 - y0, f)
+    }
+
+    /// Renders the `width` by `height` rectangle with top left pixel
+    /// `(x0, y0)`.  The samples are the same as when the region is rendered
+    /// as part of a larger image.
+    pub fn render_region<F>(&self, x0: u32, y0: u32, width: u32, height: u32, f: &F) -> Vec<f32>
+    where
+        F: Fn(f64, f64) -> [f64; 3] + Sync,
+    {
+        let mut data = vec![0f32; (width as usize) * (height as usize) * 3];
+        let fill_row = |(i, row): (usize, &mut [f32])| {
+            let y = y0 + (i as u32);
+            for (x, px) in row.chunks_exact_mut(3).enumerate() {
+                let c = self.pixel(x0 + (x as u32), y, f);
+                for k in 0..3 {
+                    px[k] = c[k] as f32;
+                }
+            }
+        };
+        let row_len = (width as usize * 3).max(1);
+        #[cfg(feature = "threads")]
+        data.par_chunks_mut(row_len).enumerate().for_each(fill_row);
+        #[cfg(not(feature = "threads"))]
+        data.chunks_mut(row_len).enumerate().for_each(fill_row);
+        data
+    }
+}
@@ -0,0 +1,208 @@
+use image::Rgb;
+use na::Point2;
+use std::io::{self, Write};
+
+use crate::color::{to_hex, Background};
+use crate::layer::Paint;
+use crate::symmetry::{transformations, SymmetryGroup};
+
+/// A stroke in canvas coordinates, before the symmetry is applied.  Like
+/// canvas pixels, the coordinates are not reduced modulo the size, so a
+/// stroke may run across several copies of the tile.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stroke {
+    /// A continuous curve.
+    Path(Vec<Point2<f64>>),
+    /// Separate points.
+    Dots(Vec<Point2<f64>>),
+}
+
+impl Stroke {
+    pub fn points(&self) -> &[Point2<f64>] {
+        match self {
+            Stroke::Path(p) | Stroke::Dots(p) => p,
+        }
+    }
+
+    /// The stroke with its coordinates multiplied by `k`.
+    pub fn scaled(&self, k: f64) -> Stroke {
+        self.map(|p| p * k)
+    }
+
+    fn map<F: Fn(&Point2<f64>) -> Point2<f64>>(&self, f: F) -> Stroke {
+        match self {
+            Stroke::Path(p) => Stroke::Path(p.iter().map(f).collect()),
+            Stroke::Dots(p) => Stroke::Dots(p.iter().map(f).collect()),
+        }
+    }
+}
+
+pub struct VectorLayer {
+    pub strokes: Vec<Stroke>,
+    /// Gradients are drawn in their first color.
+    pub paint: Paint,
+    pub source: String,
+}
+
+/// The strokes of a layered design, which tile the plane with the given
+/// symmetry group.
+pub struct VectorImage {
+    pub size: u32,
+    pub background: Background,
+    pub group: SymmetryGroup,
+    pub layers: Vec<VectorLayer>,
+}
+
+fn paint_color(paint: &Paint) -> Rgb<u8> {
+    match paint {
+        Paint::Flat(c) => *c,
+        Paint::Gradient(g) => g.at(0.),
+    }
+}
+
+/// The parts of a stroke within `margin` of the tile `[0, size)²`.  Paths
+/// are split rather than left to a clip path, since plotters ignore clipping.
+fn clip(stroke: &Stroke, size: f64, margin: f64) -> Vec<Stroke> {
+    let inside = |p: &Point2<f64>| {
+        (-margin..=(size + margin)).contains(&p.x) && (-margin..=(size + margin)).contains(&p.y)
+    };
+    match stroke {
+        Stroke::Dots(pts) => {
+            let pts: Vec<_> = pts.iter().filter(|p| inside(p)).copied().collect();
+            if pts.is_empty() {
+                Vec::new()
+            } else {
+                vec![Stroke::Dots(pts)]
+            }
+        }
+        Stroke::Path(pts) => {
+            let mut out = Vec::new();
+            let mut run: Vec<Point2<f64>> = Vec::new();
+            for seg in pts.windows(2) {
+                if inside(&seg[0]) || inside(&seg[1]) {
+                    if run.is_empty() {
+                        run.push(seg[0]);
+                    }
+                    run.push(seg[1]);
+                } else if !run.is_empty() {
+                    out.push(Stroke::Path(std::mem::take(&mut run)));
+                }
+            }
+            if pts.len() == 1 && inside(&pts[0]) {
+                run.push(pts[0]);
+            }
+            if !run.is_empty() {
+                out.push(Stroke::Path(run));
+            }
+            out
+        }
+    }
+}
+
+/// The pieces of the copies of a stroke, translated by multiples of `size`,
+/// that overlap the tile `[0, size)²`.
+fn wrapped_copies(stroke: &Stroke, size: f64, margin: f64) -> Vec<Stroke> {
+    let pts = stroke.points();
+    if pts.is_empty() {
+        return Vec::new();
+    }
+    let (mut lo, mut hi) = (pts[0], pts[0]);
+    for p in pts {
+        lo = Point2::new(lo.x.min(p.x), lo.y.min(p.y));
+        hi = Point2::new(hi.x.max(p.x), hi.y.max(p.y));
+    }
+    let range = |l: f64, h: f64| {
+        let first = ((l - margin) / size).floor() as i64;
+        let last = ((h + margin) / size).floor() as i64;
+        first..=last
+    };
+    let mut out = Vec::new();
+    for i in range(lo.x, hi.x) {
+        for j in range(lo.y, hi.y) {
+            let (dx, dy) = ((i as f64) * size, (j as f64) * size);
+            let copy = stroke.map(|p| Point2::new(p.x - dx, p.y - dy));
+            out.extend(clip(&copy, size, margin));
+        }
+    }
+    out
+}
+
+/// Canvas coordinates are `(row, column)`, and pixel `n` covers `[n, n + 1)`
+/// in the SVG, so the image and the SVG line up.
+fn svg_point(p: &Point2<f64>) -> (f64, f64) {
+    (p.y + 0.5, p.x + 0.5)
+}
+
+/// Writes the strokes as an SVG tile, with each stroke repeated through the
+/// symmetries of the group and the periodic copies that overlap the tile.
+/// Each layer is a group with a single color, suitable for plotting.
+pub fn write_svg<W: Write>(img: &VectorImage, stroke_width: f64, w: &mut W) -> io::Result<()> {
+    let size = img.size as f64;
+    let transforms = transformations(img.group, 0.5 * size);
+    writeln!(
+        w,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{0}\" viewBox=\"0 0 {0} {0}\">",
+        img.size
+    )?;
+    writeln!(
+        w,
+        "<defs><clipPath id=\"tile\"><rect width=\"{0}\" height=\"{0}\"/></clipPath></defs>",
+        img.size
+    )?;
+    if let Background::Color(c) = img.background {
+        writeln!(
+            w,
+            "<rect width=\"{0}\" height=\"{0}\" fill=\"{1}\"/>",
+            img.size,
+            to_hex(c)
+        )?;
+    }
+    writeln!(w, "<g clip-path=\"url(#tile)\">")?;
+    for (i, layer) in img.layers.iter().enumerate() {
+        let color = to_hex(paint_color(&layer.paint));
+        writeln!(
+            w,
+            "<g id=\"layer-{}\" data-source=\"{}\" stroke=\"{}\" fill=\"none\" stroke-width=\"{}\" stroke-linecap=\"round\" stroke-linejoin=\"round\">",
+            i, layer.source, color, stroke_width
+        )?;
+        for stroke in &layer.strokes {
+            for tr in &transforms {
+                let image = stroke.map(|p| tr.apply(p));
+                for copy in wrapped_copies(&image, size, stroke_width) {
+                    write_stroke(&copy, &color, stroke_width, w)?;
+                }
+            }
+        }
+        writeln!(w, "</g>")?;
+    }
+    writeln!(w, "</g>")?;
+    writeln!(w, "</svg>")
+}
+
+fn write_stroke<W: Write>(stroke: &Stroke, color: &str, width: f64, w: &mut W) -> io::Result<()> {
+    match stroke {
+        Stroke::Path(pts) => {
+            write!(w, "<polyline points=\"")?;
+            for (i, p) in pts.iter().enumerate() {
+                let (x, y) = svg_point(p);
+                let sep = if i == 0 { "" } else { " " };
+                write!(w, "{}{:.2},{:.2}", sep, x, y)?;
+            }
+            writeln!(w, "\"/>")
+        }
+        Stroke::Dots(pts) => {
+            for p in pts {
+                let (x, y) = svg_point(p);
+                writeln!(
+                    w,
+                    "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{:.2}\" fill=\"{}\" stroke=\"none\"/>",
+                    x,
+                    y,
+                    0.5 * width,
+                    color
+                )?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use ndarray::Array2;
 use std::ops::Index;
 
-use crate::canvas::{Coord, WrapCanvas};
+use crate::canvas::{Coord, WrapCanvas, WrapDimension};
 use crate::symmetry::{transformations, SymmetryGroup, Transformation};
 
 pub struct SymmetricCanvas<T> {
@@ -27,6 +27,11 @@ impl<T> SymmetricCanvas<T> {
             group,
         }
     }
+
+    /// The orbits of the points of the canvas under its group.
+    pub fn orbits(&self) -> Orbits {
+        orbits(self.group, (self.size() / 2) as u32)
+    }
 }
 
 impl<T: Copy> SymmetricCanvas<T> {
@@ -101,3 +106,62 @@ impl<T> Index<Coord> for SymmetricCanvas<T> {
         &self.canvas[idx]
     }
 }
+
+/// A point that stands for its orbit under a group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Orbit {
+    pub representative: Coord,
+    /// The number of transformations of the group that fix the point, which
+    /// is more than one on mirror lines and rotation centres.  The orbit
+    /// has `num_symmetries() / stabilizer` points.
+    pub stabilizer: usize,
+}
+
+/// An iterator over the orbits of the points of a square canvas, each
+/// represented by its first point in the order of the canvas array.
+pub struct Orbits {
+    transforms: Vec<Transformation<i32>>,
+    dims: WrapDimension,
+    visited: Array2<bool>,
+    next: usize,
+}
+
+/// The orbits of the points of a square canvas with side `2 * hsz` under
+/// the group.  Each transformation is applied once per orbit rather than
+/// once per point.
+pub fn orbits(group: SymmetryGroup, hsz: u32) -> Orbits {
+    let size = 2 * hsz as usize;
+    Orbits {
+        transforms: transformations(group, hsz as i32),
+        dims: WrapDimension::new(size as i32, size as i32),
+        visited: Array2::from_elem((size, size), false),
+        next: 0,
+    }
+}
+
+impl Iterator for Orbits {
+    type Item = Orbit;
+
+    fn next(&mut self) -> Option<Orbit> {
+        let size = self.visited.shape()[1];
+        while self.next < self.visited.len() {
+            let idx = (self.next / size, self.next % size);
+            self.next += 1;
+            if self.visited[idx] {
+                continue;
+            }
+            let pt = Coord::new(idx.1 as i32, idx.0 as i32);
+            let mut stabilizer = 0;
+            for t in &self.transforms {
+                let image = self.dims.compute_index(&t.apply(&pt));
+                stabilizer += (image == idx) as usize;
+                self.visited[image] = true;
+            }
+            return Some(Orbit {
+                representative: pt,
+                stabilizer,
+            });
+        }
+        None
+    }
+}
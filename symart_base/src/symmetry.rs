@@ -68,6 +68,12 @@ impl<T: Scalar + Ring + Copy> Transformation<T> {
         Point2::from(self.matrix * pt.coords + self.offset)
     }
 
+    /// The linear part of the transformation, which is how it acts on
+    /// vectors.
+    pub fn matrix(&self) -> &Matrix2<T> {
+        &self.matrix
+    }
+
     pub fn new(xx: T, xy: T, x1: T, yx: T, yy: T, y1: T) -> Self {
         Self {
             matrix: Matrix2::new(xx, xy, yx, yy),
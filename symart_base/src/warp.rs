@@ -0,0 +1,244 @@
+//! Domain warping: every pixel of a symmetric image is moved by a smooth
+//! random displacement that commutes with the group, so that the warped
+//! image has the same symmetry.
+
+use image::{DynamicImage, ImageBuffer, Pixel};
+use na::{Matrix2, Vector2};
+use ndarray::Array2;
+
+use crate::canvas::{Coord, WrapDimension};
+use crate::fft::Plan2D;
+use crate::field::{random_real_field, FieldParams, FilterError, SpectralFilter};
+use crate::symmetric_canvas::orbits;
+use crate::symmetry::{transformations, GridNorm, SymmetryGroup};
+
+/// The side of the grid that displacements are generated on.  They are
+/// interpolated to the size of the image, so a preview is warped in the same
+/// way as the full render.
+const FIELD_SIZE: usize = 256;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WarpParams {
+    #[serde(default)]
+    pub filter: SpectralFilter,
+    /// The exponent of the power law filters.  Larger exponents give
+    /// smoother warps.
+    pub exponent: f64,
+    /// The root mean square displacement, as a fraction of the tile.
+    pub amount: f64,
+}
+
+/// A value of a pixel that can be interpolated.
+pub trait Interpolate: Copy {
+    fn to_f32(self) -> f32;
+    fn from_f32(v: f32) -> Self;
+}
+
+impl Interpolate for u8 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(v: f32) -> Self {
+        v.round() as u8
+    }
+}
+
+impl Interpolate for u16 {
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn from_f32(v: f32) -> Self {
+        v.round() as u16
+    }
+}
+
+impl Interpolate for f32 {
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn from_f32(v: f32) -> Self {
+        v
+    }
+}
+
+/// The four points around `(r, c)` on a periodic grid of side `n`, with
+/// their weights for bilinear interpolation.
+fn bilinear_weights(n: usize, r: f64, c: f64) -> [((usize, usize), f32); 4] {
+    let (r0, c0) = (r.floor(), c.floor());
+    let (fr, fc) = ((r - r0) as f32, (c - c0) as f32);
+    let (r0, c0, n) = (r0 as i64, c0 as i64, n as i64);
+    let at = |dr: i64, dc: i64| {
+        let r = (r0 + dr).rem_euclid(n) as usize;
+        let c = (c0 + dc).rem_euclid(n) as usize;
+        (r, c)
+    };
+    [
+        (at(0, 0), (1. - fr) * (1. - fc)),
+        (at(0, 1), (1. - fr) * fc),
+        (at(1, 0), fr * (1. - fc)),
+        (at(1, 1), fr * fc),
+    ]
+}
+
+/// A random displacement that is equivariant under a group: the
+/// displacement at the image of a point under a transformation of the group
+/// is the displacement at the point, moved by the linear part of the
+/// transformation.  An image that is invariant under the group, sampled at
+/// the displaced points, is therefore invariant too.
+pub struct Warp {
+    group: SymmetryGroup,
+    /// The displacement at each point of the grid, along the axes of
+    /// `Coord`, in fractions of the tile.
+    field: Array2<Vector2<f32>>,
+}
+
+impl Warp {
+    /// A random warp for images with the given symmetry.  Each component is
+    /// a real field with the filter of the parameters, and the pair is then
+    /// averaged over the group, which keeps only its equivariant part.
+    pub fn new(group: SymmetryGroup, params: &WarpParams) -> Result<Self, FilterError> {
+        params.filter.validate()?;
+        let plan = Plan2D::<f32>::cached(FIELD_SIZE, FIELD_SIZE);
+        let field = FieldParams {
+            filter: params.filter.clone(),
+            exponent: params.exponent,
+            lattice: GridNorm::from_symmetry(group),
+            ..FieldParams::new(None)
+        };
+        let fx = random_real_field(&plan, &field);
+        let fy = random_real_field(&plan, &field);
+        let dims = WrapDimension::new_from_shape(fx.shape());
+        let transforms = transformations(group, (FIELD_SIZE / 2) as i32);
+        let inverses: Vec<Matrix2<f32>> = transforms
+            .iter()
+            .map(|t| t.matrix().map(|v| v as f32).try_inverse().unwrap())
+            .collect();
+        let mut field = Array2::from_shape_fn(fx.raw_dim(), |(y, x)| {
+            let pt = Coord::new(x as i32, y as i32);
+            transforms
+                .iter()
+                .zip(&inverses)
+                .map(|(t, inv)| {
+                    let i = dims.compute_index(&t.apply(&pt));
+                    inv * Vector2::new(fx[i], fy[i])
+                })
+                .sum::<Vector2<f32>>()
+        });
+        let ms = field.iter().map(|v| v.norm_squared() as f64).sum::<f64>() / (field.len() as f64);
+        let k = (params.amount / ms.sqrt().max(f64::MIN_POSITIVE)) as f32;
+        field.mapv_inplace(|v| v * k);
+        Ok(Self { group, field })
+    }
+
+    /// The bytes used to warp a square image of side `size`, besides the
+    /// image and its warped copy.
+    pub fn memory(size: usize) -> usize {
+        size * size + 4 * FIELD_SIZE * FIELD_SIZE * std::mem::size_of::<f32>()
+    }
+
+    /// Calls `f` for each orbit of the points of a square array of side
+    /// `size`, with the point to sample, as `(row, column)`, and the
+    /// indices of the orbit.  Only one point of each orbit is displaced, so
+    /// the result is exactly symmetric even where interpolation is not.
+    fn for_each_orbit<F>(&self, size: usize, mut f: F)
+    where
+        F: FnMut((f64, f64), &[(usize, usize)]),
+    {
+        let dims = WrapDimension::new(size as i32, size as i32);
+        let transforms = transformations(self.group, (size / 2) as i32);
+        let (n, k) = (size as f64, (FIELD_SIZE as f64) / (size as f64));
+        let mut images = Vec::with_capacity(transforms.len());
+        for orbit in orbits(self.group, (size / 2) as u32) {
+            let pt = orbit.representative;
+            let (x, y) = (pt.x as f64, pt.y as f64);
+            let d: Vector2<f32> = bilinear_weights(FIELD_SIZE, y * k, x * k)
+                .iter()
+                .map(|&(i, w)| self.field[i] * w)
+                .sum();
+            images.clear();
+            images.extend(transforms.iter().map(|t| dims.compute_index(&t.apply(&pt))));
+            f((y + n * (d.y as f64), x + n * (d.x as f64)), &images);
+        }
+    }
+
+    /// Warps a square array with an even side that is symmetric under the
+    /// group, such as a layer mask.
+    pub fn apply<T: Interpolate>(&self, arr: &Array2<T>) -> Array2<T> {
+        let n = arr.shape()[0];
+        let mut out = arr.clone();
+        self.for_each_orbit(n, |(r, c), images| {
+            let v = bilinear_weights(n, r, c)
+                .iter()
+                .map(|&(i, w)| arr[i].to_f32() * w)
+                .sum();
+            let v = T::from_f32(v);
+            for &i in images {
+                out[i] = v;
+            }
+        });
+        out
+    }
+
+    /// Warps a square image with an even side that is symmetric under the
+    /// group.  Images of a type that the image crate adds later are warped
+    /// as floating point RGBA.
+    pub fn apply_image(&self, im: &DynamicImage) -> DynamicImage {
+        match im {
+            DynamicImage::ImageLuma8(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageLumaA8(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageRgb8(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageRgba8(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageLuma16(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageLumaA16(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageRgb16(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageRgba16(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageRgb32F(im) => self.apply_buffer(im).into(),
+            DynamicImage::ImageRgba32F(im) => self.apply_buffer(im).into(),
+            _ => self.apply_buffer(&im.to_rgba32f()).into(),
+        }
+    }
+
+    /// Warps an image, indexed like a layer mask, with `x` as the row.
+    /// Colors with an alpha channel are interpolated premultiplied.
+    fn apply_buffer<P>(
+        &self,
+        im: &ImageBuffer<P, Vec<P::Subpixel>>,
+    ) -> ImageBuffer<P, Vec<P::Subpixel>>
+    where
+        P: Pixel,
+        P::Subpixel: Interpolate,
+    {
+        let (n, ch, alpha) = (im.width() as usize, P::CHANNEL_COUNT as usize, P::HAS_ALPHA);
+        let mut out = im.clone();
+        let (src, dst): (&[P::Subpixel], &mut [P::Subpixel]) = (im, &mut out);
+        let idx = |(x, y): (usize, usize)| (y * n + x) * ch;
+        self.for_each_orbit(n, |(r, c), images| {
+            let mut v = [0f32; 4];
+            for (i, w) in bilinear_weights(n, r, c) {
+                let p = &src[idx(i)..][..ch];
+                let a = if alpha { p[ch - 1].to_f32() } else { 1. };
+                for k in 0..ch {
+                    v[k] += w * if alpha && k + 1 == ch {
+                        a
+                    } else {
+                        p[k].to_f32() * a
+                    };
+                }
+            }
+            if alpha {
+                let a = v[ch - 1];
+                for c in &mut v[..ch - 1] {
+                    *c = if a > 0. { *c / a } else { 0. };
+                }
+            }
+            let v = v.map(P::Subpixel::from_f32);
+            for &i in images {
+                dst[idx(i)..][..ch].copy_from_slice(&v[..ch]);
+            }
+        });
+        out
+    }
+}
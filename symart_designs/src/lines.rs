@@ -1,4 +1,3 @@
-use image::RgbImage;
 use na::{ClosedAdd, Point2, Scalar, Vector2};
 use num_traits::identities::zero;
 use ordered_float::NotNan;
@@ -9,12 +8,20 @@ use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::f64::consts::{FRAC_1_SQRT_2, PI, SQRT_2};
 use strum_macros::{Display, EnumCount, EnumIter, EnumString, IntoStaticStr};
+use thiserror::Error;
 
-use symart_base::canvas::Coord;
+use ndarray::Array2;
+use symart_base::animate::Animation;
+use symart_base::canvas::{Coord, WrapCanvas};
+use symart_base::color::{Background, Palette};
+use symart_base::layer::{Layer, LayeredImage, Paint, Shading};
+use symart_base::memory;
 use symart_base::random::NormalScaled;
+use symart_base::svg::{Stroke, VectorImage, VectorLayer};
 use symart_base::symmetric_canvas::SymmetricCanvas;
 use symart_base::symmetry::{GridNorm, SymmetryGroup};
-use symart_base::{schema, DrawResponse, SymmetryChoice};
+use symart_base::warp::{Warp, WarpParams};
+use symart_base::{schema, Animate, DrawResponse, LayeredDesign, Quality, SymmetryChoice};
 
 struct NormalDist(pub GridNorm);
 
@@ -89,6 +96,51 @@ fn unit_vector(q: f64) -> Vector2<f64> {
 pub struct LayerGenerator<'a, 'b, R: Rng + ?Sized + 'b> {
     pub canvas: &'a mut SymmetricCanvas<u8>,
     pub rng: &'b mut R,
+    /// If set, the strokes are recorded as well as drawn.
+    pub recorder: Option<Recorder>,
+}
+
+/// Collects the strokes drawn by a `LayerGenerator`.  The curves drawn by
+/// recursive subdivision place their midpoints first, so each point is
+/// recorded with its parameter along the curve and the points are sorted
+/// when the stroke is finished.
+#[derive(Default)]
+pub struct Recorder {
+    strokes: Vec<(bool, Vec<(f64, CoordF)>)>,
+    current: usize,
+    /// The parameter interval of the current subdivision step, or `None` if
+    /// points are drawn in order.
+    interval: Option<(f64, f64)>,
+}
+
+impl Recorder {
+    pub fn into_strokes(self) -> Vec<Stroke> {
+        self.strokes
+            .into_iter()
+            .filter(|(_, pts)| !pts.is_empty())
+            .map(|(dots, mut pts)| {
+                pts.sort_by(|a, b| a.0.total_cmp(&b.0));
+                let pts = pts.into_iter().map(|(_, p)| p).collect();
+                if dots {
+                    Stroke::Dots(pts)
+                } else {
+                    Stroke::Path(pts)
+                }
+            })
+            .collect()
+    }
+
+    fn record(&mut self, pt: &CoordF) {
+        if self.strokes.is_empty() {
+            self.strokes.push((true, Vec::new()));
+        }
+        let stroke = &mut self.strokes[self.current].1;
+        let key = match self.interval {
+            Some((a, b)) => 0.5 * (a + b),
+            None => stroke.len() as f64,
+        };
+        stroke.push((key, *pt));
+    }
 }
 
 #[derive(
@@ -152,7 +204,49 @@ impl Ord for StarPathNode {
 
 impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
     pub fn new(canvas: &'a mut SymmetricCanvas<u8>, rng: &'b mut R) -> Self {
-        Self { canvas, rng }
+        Self {
+            canvas,
+            rng,
+            recorder: None,
+        }
+    }
+
+    /// Starts a new stroke.  `subdivided` should be set for curves drawn by
+    /// recursive subdivision.
+    fn begin_stroke(&mut self, dots: bool, subdivided: bool) {
+        if let Some(rec) = &mut self.recorder {
+            rec.strokes.push((dots, Vec::new()));
+            rec.current = rec.strokes.len() - 1;
+            rec.interval = if subdivided { Some((0., 1.)) } else { None };
+        }
+    }
+
+    fn begin_path(&mut self) {
+        self.begin_stroke(false, false);
+    }
+
+    /// Records a point of the current stroke without drawing it.
+    fn record(&mut self, pt: &CoordF) {
+        if let Some(rec) = &mut self.recorder {
+            rec.record(pt);
+        }
+    }
+
+    /// Runs `f` on the first or second half of the current subdivision
+    /// interval.
+    fn subdivide<F: FnOnce(&mut Self)>(&mut self, second: bool, f: F) {
+        let saved = self.recorder.as_mut().and_then(|rec| {
+            let old = rec.interval;
+            if let Some((a, b)) = old {
+                let m = 0.5 * (a + b);
+                rec.interval = Some(if second { (m, b) } else { (a, m) });
+            }
+            old
+        });
+        f(self);
+        if let Some(rec) = &mut self.recorder {
+            rec.interval = saved;
+        }
     }
 
     fn norm_type(&self) -> GridNorm {
@@ -186,15 +280,12 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
     }
 
     fn draw_dot_default(&mut self, pt: &CoordF) {
+        self.record(pt);
         self.draw_dot(pt, 5, 1.);
     }
 
     fn draw_dot(&mut self, pt: &CoordF, radius: i32, brightness: f64) {
-        let denom = brightness
-            * match self.norm_type() {
-                GridNorm::Square => 5.,
-                GridNorm::Hexagonal => 4.33,
-            };
+        let denom = brightness * brightness_denominator(self.norm_type());
         let num = denom * 255.;
         let closest = from_float(*pt);
         for x in (closest.x - radius)..=(closest.x + radius) {
@@ -213,10 +304,14 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         let mid = CoordF::new(mx, my) + off;
         self.draw_dot_default(&mid);
         if self.norm(&(mid - p1)) >= dist {
-            self.draw_smooth_arc(p1, &mid, curvature / 2., stdev / 2., dist);
+            self.subdivide(false, |s| {
+                s.draw_smooth_arc(p1, &mid, curvature / 2., stdev / 2., dist)
+            });
         }
         if self.norm(&(mid - p2)) >= dist {
-            self.draw_smooth_arc(&mid, p2, curvature / 2., stdev / 2., dist);
+            self.subdivide(true, |s| {
+                s.draw_smooth_arc(&mid, p2, curvature / 2., stdev / 2., dist)
+            });
         }
     }
 
@@ -224,10 +319,14 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         let mid = midpoint(p1, p2) + stdev * self.random_normal();
         self.draw_dot_default(&mid);
         if self.norm(&(mid - p1)) >= dist {
-            self.draw_line(p1, &mid, stdev * factor, dist, factor);
+            self.subdivide(false, |s| {
+                s.draw_line(p1, &mid, stdev * factor, dist, factor)
+            });
         }
         if self.norm(&(mid - p2)) >= dist {
-            self.draw_line(&mid, p2, stdev * factor, dist, factor);
+            self.subdivide(true, |s| {
+                s.draw_line(&mid, p2, stdev * factor, dist, factor)
+            });
         }
     }
 
@@ -250,10 +349,14 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         self.draw_dot_default(&mid);
         let new_stdev = stdev * (0.25 * SQRT_2);
         if self.norm(&(mid - p1)) >= dist {
-            self.draw_smooth_line_new(p1, &(0.5 * v1), &mid, &vmid, new_stdev, dist);
+            self.subdivide(false, |s| {
+                s.draw_smooth_line_new(p1, &(0.5 * v1), &mid, &vmid, new_stdev, dist)
+            });
         }
         if self.norm(&(mid - p1)) >= dist {
-            self.draw_smooth_line_new(&mid, &vmid, p2, &(0.5 * v2), new_stdev, dist);
+            self.subdivide(true, |s| {
+                s.draw_smooth_line_new(&mid, &vmid, p2, &(0.5 * v2), new_stdev, dist)
+            });
         }
     }
 
@@ -282,9 +385,11 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         let pt = center + stdev * self.random_normal();
         let clusters = Uniform::new(0, max_depth).sample(self.rng);
         for _ in 0..clusters {
+            self.begin_stroke(false, true);
             self.draw_smooth_line(center, &pt, stdev / 2., 1.);
             self.draw_cluster(&pt, stdev / 2., max_depth - 1);
         }
+        self.begin_stroke(true, false);
         self.draw_dot_default(center);
     }
 
@@ -298,6 +403,7 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
             let angle = angle_mult * ((i as f64) + offset) / (petals as f64);
             let mut v = unit_vector(angle);
             v += 0.07 * self.random_normal();
+            self.begin_path();
             for _ in 0..steps {
                 v += 0.07 * self.random_normal();
                 pt += v;
@@ -341,6 +447,7 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         let mut q = Uniform::new(0., 2. * PI).sample(self.rng);
         let mut pt = self.random_point();
         let mut t = 0.;
+        self.begin_path();
         while t < len {
             self.draw_dot_default(&pt);
             q += SWIRL_EPS * (t * k1 + (1. - t) * k2) / len;
@@ -353,6 +460,8 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         let mut pt = *start;
         let n = Poisson::new(20.).unwrap().sample(self.rng) as usize;
         let v = unit_vector(q);
+        self.begin_path();
+        self.record(start);
         for _ in 0..n {
             pt += v;
             self.draw_dot_default(&pt);
@@ -375,10 +484,12 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         match design {
             Arc => {
                 let (start, end) = self.random_endpoints(2);
+                self.begin_stroke(false, true);
                 self.draw_smooth_arc(&start, &end, 0.8, 30., 1.);
             }
             Beads => {
                 let (start, end) = self.random_endpoints(2);
+                self.begin_stroke(true, true);
                 self.draw_smooth_line(&start, &end, 100., 100.);
             }
             Cluster => {
@@ -394,16 +505,19 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
                 let pt = self.random_point();
                 let sz = self.size() as u32;
                 let fp = self.fractal_prob();
+                self.begin_stroke(true, false);
                 self.draw_fractal(&pt, sz, fp);
             }
             Granules => self.draw_granules(),
             Line => {
                 let (start, end) = self.random_endpoints(2);
+                self.begin_stroke(false, true);
                 self.draw_smooth_line(&start, &end, 100., 1.);
             }
             Loop => {
                 let (p1, p2) = self.random_endpoints(1);
                 let v = (self.size() as f64) * self.random_normal();
+                self.begin_stroke(false, true);
                 self.draw_smooth_line_new(&p1, &v, &p2, &v, 200., 1.);
             }
             Orbit => self.draw_orbit(),
@@ -413,6 +527,7 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
                 let sigma = e * (self.size() as f64) * 0.07;
                 let p1 = self.random_point();
                 let p2 = p1 + sigma * self.random_normal();
+                self.begin_stroke(false, true);
                 self.draw_line(&p1, &p2, sigma / 2., 1., FRAC_1_SQRT_2);
             }
             Swirl => loop {
@@ -433,6 +548,7 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         let mut pt = self.random_point();
         let mu = ((self.size() * self.size()) as f64) / (10. * (self.num_symmetries() as f64));
         let steps = Poisson::new(mu).unwrap().sample(self.rng) as usize;
+        self.begin_stroke(true, false);
         for _ in 0..steps {
             let z = NormalScaled(3.).sample(self.rng);
             pt += self.random_normal() / z;
@@ -445,6 +561,7 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         let mut q = Uniform::new(0., 2. * PI).sample(self.rng);
         let mut dq = Cauchy::new(0., 0.167).unwrap().sample(self.rng);
         let steps = Poisson::new(2500.).unwrap().sample(self.rng) as usize;
+        self.begin_path();
         for _ in 0..steps {
             dq *= 0.97;
             dq += Cauchy::new(0., 0.005).unwrap().sample(self.rng);
@@ -466,8 +583,15 @@ impl<'a, 'b, R: Rng + ?Sized + 'b> LayerGenerator<'a, 'b, R> {
         };
         let scale = (self.size() as f64) / (2. * PI);
         let mut t = 12. / (self.num_symmetries() as f64);
+        let first = self.recorder.as_ref().map_or(0, |rec| rec.strokes.len());
+        for _ in 0..3 {
+            self.begin_path();
+        }
         while t >= 0. {
-            for pt in q.iter() {
+            for (i, pt) in q.iter().enumerate() {
+                if let Some(rec) = &mut self.recorder {
+                    rec.current = first + i;
+                }
                 self.draw_dot_default(&(scale * pt));
             }
             for i in 1..3 {
@@ -533,6 +657,31 @@ pub struct Lines {
     pub size: u32,
     pub colors: usize,
     pub designs: Vec<Design>,
+    #[serde(default)]
+    pub background: Background,
+    #[serde(default)]
+    pub shading: Shading,
+    #[serde(default)]
+    pub palette: Palette,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// If set, the strokes are generated for a tile of this size and then
+    /// scaled to `size`, so that the composition does not depend on `size`.
+    #[serde(default)]
+    pub reference_size: Option<u32>,
+    /// If set, the pixels of the image are moved by a random displacement
+    /// with the same symmetry, after the layers are drawn.  Vector output
+    /// cannot be warped.
+    #[serde(default)]
+    pub warp: Option<WarpParams>,
+    #[serde(default)]
+    pub quality: Quality,
+}
+
+#[derive(Error, Debug)]
+pub enum LinesError {
+    #[error("Vector output cannot be warped")]
+    WarpedVector,
 }
 
 pub fn lines_designs() -> serde_json::Value {
@@ -550,6 +699,215 @@ pub fn lines_designs() -> serde_json::Value {
     })
 }
 
+impl Lines {
+    /// The size of the tile that the strokes are generated on.
+    fn generation_size(&self) -> u32 {
+        self.reference_size.unwrap_or(self.size)
+    }
+
+    /// The size of the output.  A preview is drawn from the same strokes as
+    /// the full render, scaled down.
+    fn output_size(&self) -> u32 {
+        let k = self.quality.scale(self.size, self.size);
+        ((((self.size as f64) * k / 2.).round() as u32) * 2).max(2)
+    }
+
+    fn scale(&self) -> f64 {
+        (self.output_size() as f64) / (self.generation_size() as f64)
+    }
+
+    /// The bytes used by each layer while it is generated, and by the
+    /// output image.
+    fn layer_memory(&self) -> (usize, usize) {
+        let gen_size = self.generation_size() as usize;
+        let per_pixel = if self.designs.contains(&Design::Star) {
+            // The canvas, two `f64` distance maps and a `bool` mark map.
+            1 + 2 * std::mem::size_of::<f64>() + 1
+        } else {
+            1
+        };
+        let mut layer_bytes = gen_size * gen_size * per_pixel;
+        let size = self.output_size();
+        if self.generation_size() != size {
+            layer_bytes += (size as usize) * (size as usize);
+        }
+        let alpha = self.background == Background::Transparent;
+        let reserved = memory::image_bytes(size, size, alpha);
+        (layer_bytes, reserved)
+    }
+
+    /// The masks of the layers, with the design that generated each one and,
+    /// if `record` is set, its strokes in generation coordinates.
+    fn layer_masks(
+        &self,
+        sym: SymmetryGroup,
+        record: bool,
+    ) -> impl Iterator<Item = (Design, SymmetricCanvas<u8>, Vec<Stroke>)> + '_ {
+        let (layer_bytes, reserved) = self.layer_memory();
+        let gen_size = self.generation_size();
+        let size = self.output_size();
+        let scaled = gen_size != size;
+        symart_base::make_layers_n_sized(self.colors, layer_bytes, reserved, move |_| {
+            let mut canvas = SymmetricCanvas::new(sym, gen_size / 2);
+            let (design, strokes) = symart_base::rng::sample_fn(|rng| {
+                let idx = Uniform::new(0, self.designs.len()).sample(rng);
+                let design = self.designs[idx];
+                let mut lg = LayerGenerator::new(&mut canvas, rng);
+                if record || scaled {
+                    lg.recorder = Some(Recorder::default());
+                }
+                lg.generate(design);
+                let strokes = lg.recorder.map_or(Vec::new(), Recorder::into_strokes);
+                (design, strokes)
+            });
+            if scaled {
+                canvas = if design == Design::Star {
+                    upscale(&canvas, size)
+                } else {
+                    let mut target = SymmetricCanvas::new(sym, size / 2);
+                    rasterize_strokes(&mut target, &strokes, self.scale());
+                    target
+                };
+            }
+            (design, canvas, strokes)
+        })
+    }
+
+    /// The strokes of each layer, for vector output.  The Star design is
+    /// filled pixel by pixel rather than stroked, so it has no strokes.
+    pub fn draw_vector(&self) -> Result<VectorImage, LinesError> {
+        if self.warp.is_some() {
+            return Err(LinesError::WarpedVector);
+        }
+        symart_base::rng::with_optional_seed(self.seed, || {
+            let sym: SymmetryGroup = self.symmetry.into();
+            let k = self.scale();
+            let layers = self
+                .layer_masks(sym, true)
+                .map(|(design, _, strokes)| VectorLayer {
+                    strokes: strokes.iter().map(|s| s.scaled(k)).collect(),
+                    paint: Paint::random(self.shading, &self.palette),
+                    source: design.to_string(),
+                })
+                .collect();
+            Ok(VectorImage {
+                size: self.output_size(),
+                background: self.background,
+                group: sym,
+                layers,
+            })
+        })
+    }
+}
+
+fn brightness_denominator(norm: GridNorm) -> f64 {
+    match norm {
+        GridNorm::Square => 5.,
+        GridNorm::Hexagonal => 4.33,
+    }
+}
+
+/// The bilinear form of the grid norm, so that `bilinear(n, v, v)` is
+/// `n.norm(v)`.
+fn bilinear(norm: GridNorm, u: &Vector2<f64>, v: &Vector2<f64>) -> f64 {
+    match norm {
+        GridNorm::Square => u.dot(v),
+        GridNorm::Hexagonal => u.x * v.x + 0.5 * (u.x * v.y + u.y * v.x) + u.y * v.y,
+    }
+}
+
+/// Draws recorded strokes, with coordinates multiplied by `scale`.  Each
+/// segment of a path is drawn with the same profile as a dot of
+/// `draw_dot_default`, stretched by `scale`, at its closest point, which is
+/// the limit of the densely spaced dots that the generator draws.
+fn rasterize_strokes(canvas: &mut SymmetricCanvas<u8>, strokes: &[Stroke], scale: f64) {
+    let norm = GridNorm::from_symmetry(canvas.symmetry_group());
+    let denom = brightness_denominator(norm);
+    let num = denom * 255.;
+    let radius = (5. * scale).ceil() as i32;
+    let inv_scale2 = 1. / (scale * scale);
+    let mut segment = |a: CoordF, b: CoordF| {
+        let d = b - a;
+        let dd = bilinear(norm, &d, &d);
+        let lo = from_float(CoordF::new(a.x.min(b.x), a.y.min(b.y)));
+        let hi = from_float(CoordF::new(a.x.max(b.x), a.y.max(b.y)));
+        for x in (lo.x - radius)..=(hi.x + radius) {
+            for y in (lo.y - radius)..=(hi.y + radius) {
+                let pix = Point2::new(x, y);
+                let pf = to_float(pix);
+                let t = if dd > 0. {
+                    (bilinear(norm, &(pf - a), &d) / dd).clamp(0., 1.)
+                } else {
+                    0.
+                };
+                let c = a + t * d;
+                let off = pf - c;
+                if off.x.abs() > (radius as f64) + 0.5 || off.y.abs() > (radius as f64) + 0.5 {
+                    continue;
+                }
+                let v = (num / (denom + norm.norm(&off) * inv_scale2)) as u8;
+                if v > canvas[pix] {
+                    canvas.set(&pix, v);
+                }
+            }
+        }
+    };
+    for stroke in strokes {
+        let pts: Vec<CoordF> = stroke.points().iter().map(|p| p * scale).collect();
+        match stroke {
+            Stroke::Path(_) if pts.len() > 1 => {
+                for w in pts.windows(2) {
+                    segment(w[0], w[1]);
+                }
+            }
+            _ => {
+                for p in pts {
+                    segment(p, p);
+                }
+            }
+        }
+    }
+}
+
+/// The first `t` of the points of the strokes, in the order they were drawn.
+fn partial_strokes(strokes: &[Stroke], t: f64) -> Vec<Stroke> {
+    let total: usize = strokes.iter().map(|s| s.points().len()).sum();
+    let mut left = ((total as f64) * t).round() as usize;
+    let mut out = Vec::new();
+    for stroke in strokes {
+        if left == 0 {
+            break;
+        }
+        let pts = stroke.points();
+        let m = pts.len().min(left);
+        left -= m;
+        out.push(match stroke {
+            Stroke::Path(_) => Stroke::Path(pts[..m].to_vec()),
+            Stroke::Dots(_) => Stroke::Dots(pts[..m].to_vec()),
+        });
+    }
+    out
+}
+
+/// Resamples a canvas to a new size with periodic bilinear interpolation.
+/// The scaling is about the origin, so the symmetry is preserved.
+fn upscale(src: &SymmetricCanvas<u8>, size: u32) -> SymmetricCanvas<u8> {
+    let arr: &Array2<u8> = src.as_ref();
+    let n = arr.shape()[0] as i64;
+    let k = (n as f64) / (size as f64);
+    let at = |r: i64, c: i64| arr[(r.rem_euclid(n) as usize, c.rem_euclid(n) as usize)] as f64;
+    let wc = WrapCanvas::from_fn(size, size, |r, c| {
+        let (u, v) = ((r as f64) * k, (c as f64) * k);
+        let (r0, c0) = (u.floor(), v.floor());
+        let (fr, fc) = (u - r0, v - c0);
+        let (r0, c0) = (r0 as i64, c0 as i64);
+        let top = at(r0, c0) * (1. - fc) + at(r0, c0 + 1) * fc;
+        let bottom = at(r0 + 1, c0) * (1. - fc) + at(r0 + 1, c0 + 1) * fc;
+        (top * (1. - fr) + bottom * fr).round() as u8
+    });
+    SymmetricCanvas::from_wrap_canvas(wc, src.symmetry_group())
+}
+
 impl symart_base::Design for Lines {
     fn name() -> &'static str {
         "Lines"
@@ -563,35 +921,152 @@ impl symart_base::Design for Lines {
                 "symmetry": schema::symmetries(),
                 "size": schema::size_even(),
                 "colors": schema::num_colors(),
-                "designs": lines_designs()
+                "designs": lines_designs(),
+                "background": schema::background(),
+                "shading": schema::shading(),
+                "palette": schema::palette(),
+                "seed": schema::seed(),
+                "reference_size": {
+                    "type": "integer",
+                    "title": "Reference size",
+                    "minimum": 2,
+                    "maximum": 65536,
+                    "multipleOf": 2
+                },
+                "warp": schema::warp(),
+                "quality": schema::quality()
             },
             "required": ["size", "symmetry", "colors", "designs"]
         })
     }
 
     fn draw(&self) -> Result<DrawResponse, Box<dyn std::error::Error>> {
-        let sym: SymmetryGroup = self.symmetry.into();
-        let mut im = RgbImage::new(self.size, self.size);
-        symart_base::make_layers(self.colors, || {
-            let mut canvas = SymmetricCanvas::new(sym, self.size / 2);
-            symart_base::rng::sample_fn(|rng| {
-                let idx = Uniform::new(0, self.designs.len()).sample(rng);
-                let design = self.designs[idx];
-                let mut lg = LayerGenerator {
-                    canvas: &mut canvas,
-                    rng,
-                };
-                lg.generate(design);
+        symart_base::rng::with_optional_seed(self.seed, || {
+            let sym: SymmetryGroup = self.symmetry.into();
+            let size = self.output_size();
+            let mut im = symart_base::layer::background_image(size, size, self.background);
+            self.layer_masks(sym, false).for_each(|(_, layer, _)| {
+                let paint = Paint::random(self.shading, &self.palette);
+                symart_base::layer::merge_layer(&mut im, layer.as_ref(), &paint);
             });
-            canvas
+            if let Some(params) = &self.warp {
+                im = Warp::new(sym, params)?.apply_image(&im);
+            }
+            Ok(DrawResponse {
+                im,
+                sym: sym.into(),
+            })
         })
-        .for_each(|layer| {
-            let col = symart_base::rng::sample(symart_base::random::Color);
-            symart_base::layer::merge_one(&mut im, layer.as_ref(), image::Rgb(col));
-        });
-        Ok(DrawResponse {
-            im,
-            sym: sym.into(),
+    }
+
+    fn memory_estimate(&self) -> usize {
+        let (layer_bytes, reserved) = self.layer_memory();
+        let layers = memory::layered_estimate(self.colors, layer_bytes, reserved);
+        match self.warp {
+            // The image is warped into a copy once the layers are done.
+            Some(_) => layers.max(2 * reserved + Warp::memory(self.output_size() as usize)),
+            None => layers,
+        }
+    }
+}
+
+impl LayeredDesign for Lines {
+    fn draw_layers(&self) -> Result<LayeredImage, Box<dyn std::error::Error>> {
+        symart_base::rng::with_optional_seed(self.seed, || {
+            let sym: SymmetryGroup = self.symmetry.into();
+            let mut layers: Vec<Layer> = self
+                .layer_masks(sym, false)
+                .map(|(design, mask, _)| {
+                    let paint = Paint::random(self.shading, &self.palette);
+                    Layer::from_u8(mask.as_ref(), paint, design.to_string())
+                })
+                .collect();
+            if let Some(params) = &self.warp {
+                let warp = Warp::new(sym, params)?;
+                for layer in &mut layers {
+                    layer.mask = warp.apply(&layer.mask);
+                }
+            }
+            let size = self.output_size();
+            Ok(LayeredImage {
+                width: size,
+                height: size,
+                background: self.background,
+                layers,
+                sym: sym.into(),
+            })
+        })
+    }
+
+    fn layers_memory_estimate(&self) -> usize {
+        let (layer_bytes, reserved) = self.layer_memory();
+        let size = self.output_size() as usize;
+        let mask = size * size * std::mem::size_of::<u16>();
+        let kept =
+            memory::kept_layers_estimate(self.colors, layer_bytes, reserved, self.colors * mask);
+        match self.warp {
+            // Each mask is warped into a copy in turn.
+            Some(_) => kept.max(self.colors * mask + mask + Warp::memory(size)),
+            None => kept,
+        }
+    }
+}
+
+impl Animate for Lines {
+    /// All layers are drawn stroke by stroke at the same time.  The Star
+    /// design has no strokes, so it fades in instead.  The warp stays the
+    /// same throughout.  The frames rasterize the recorded strokes, so the
+    /// last one has the strokes of `draw` but can differ from it slightly
+    /// where they are antialiased.
+    fn animation(&self) -> Result<Animation, Box<dyn std::error::Error>> {
+        symart_base::rng::with_optional_seed(self.seed, || {
+            let sym: SymmetryGroup = self.symmetry.into();
+            let (size, scale) = (self.output_size(), self.scale());
+            let layers: Vec<_> = self
+                .layer_masks(sym, true)
+                .map(|(design, canvas, strokes)| {
+                    let star = (design == Design::Star).then_some(canvas);
+                    (star, strokes)
+                })
+                .collect();
+            let paints: Vec<Paint> = layers
+                .iter()
+                .map(|_| Paint::random(self.shading, &self.palette))
+                .collect();
+            let warp = self
+                .warp
+                .as_ref()
+                .map(|params| Warp::new(sym, params))
+                .transpose()?;
+            let background = self.background;
+            let frame = move |t: f64| {
+                let mut im = symart_base::layer::background_image(size, size, background);
+                for ((star, strokes), paint) in layers.iter().zip(&paints) {
+                    let mask: Array2<u8> = match star {
+                        Some(canvas) => {
+                            let arr: &Array2<u8> = canvas.as_ref();
+                            arr.mapv(|v| ((v as f64) * t).round() as u8)
+                        }
+                        None => {
+                            let mut canvas = SymmetricCanvas::new(sym, size / 2);
+                            rasterize_strokes(&mut canvas, &partial_strokes(strokes, t), scale);
+                            canvas.into()
+                        }
+                    };
+                    symart_base::layer::merge_layer(&mut im, &mask, paint);
+                }
+                if let Some(warp) = &warp {
+                    im = warp.apply_image(&im);
+                }
+                DrawResponse {
+                    im,
+                    sym: sym.into(),
+                }
+            };
+            Ok(Animation {
+                frame: Box::new(frame),
+                periodic: false,
+            })
         })
     }
 }
@@ -1,4 +1,3 @@
-use image::RgbImage;
 use na::{Matrix4x2, Matrix6, Vector2, Vector4, Vector6};
 use nalgebra as na;
 use num_complex::Complex64;
@@ -7,8 +6,13 @@ use rand::Rng;
 use rand_distr::Uniform;
 use std::f64::consts::FRAC_1_SQRT_2;
 use std::f64::consts::PI;
+use std::sync::Arc;
+use strum_macros::{Display, EnumIter, EnumString};
+use symart_base::animate::{mix, mix_angle, pick, Animation, MorphError};
+use symart_base::color::{Colormap, Palette};
 use symart_base::random::{ComplexStdNormal, Fraction, NormalScaled, Slice};
-use symart_base::{schema, DrawResponse};
+use symart_base::supersample::{samples_per_axis, PixelFilter, SamplePattern, Supersampler};
+use symart_base::{schema, Animate, DrawResponse, Morph, PixelDesign, PixelFn, Quality};
 use thiserror::Error;
 
 type V4 = nalgebra::Matrix<f64, na::U4, na::U1, na::ArrayStorage<f64, 4, 1>>;
@@ -143,20 +147,36 @@ static FLIPS_5: [fn(V4) -> V4; 4] = [ident, flip10_1, flip10_2, flip10_3];
 static FLIPS_8: [fn(V4) -> V4; 4] = [ident, flip8_1, flip8_2, flip8_3];
 static FLIPS_12: [fn(V4) -> V4; 4] = [ident, flip12_1, flip12_2, flip12_3];
 
+/// The state of an orbit after the last iteration.  `dist` lies in
+/// `[-1, 1]` and `angle` is the argument of the sum whose real part is
+/// `dist`.
+#[derive(Clone, Copy, Debug)]
+struct TrapValue {
+    dist: f64,
+    angle: f64,
+}
+
 trait TrapRunner {
-    type Point;
+    type Point: Copy;
     fn new_random<R: Rng + ?Sized>(rng: &mut R) -> Self;
-    fn embed(&self, v: Vector2<f64>) -> Self::Point;
+    /// Embeds a point of the plane, with the offset shifted by `shift`.
+    fn embed(&self, v: Vector2<f64>, shift: V4) -> Self::Point;
     fn iterate(&self, p: Self::Point) -> Self::Point;
     fn dist(&self, p: Self::Point) -> f64;
+    fn angle(&self, p: Self::Point) -> f64;
     fn num_iters(&self) -> usize;
-    fn run(&self, v2: Vector2<f64>) -> u8 {
-        let mut v = self.embed(v2);
-        for _ in 0..self.num_iters() {
+    /// The runner a fraction `t` of the way to `other`.  The coefficients
+    /// and offsets are blended, and the transforms switch halfway.
+    fn mix(&self, other: &Self, t: f64) -> Self;
+    fn run(&self, v2: Vector2<f64>, shift: V4, iters: usize) -> TrapValue {
+        let mut v = self.embed(v2, shift);
+        for _ in 0..iters {
             v = self.iterate(v)
         }
-        let dm = self.dist(v);
-        (127.999 * (dm + 1.0)) as u8
+        TrapValue {
+            dist: self.dist(v),
+            angle: self.angle(v),
+        }
     }
 }
 
@@ -190,8 +210,8 @@ impl TrapRunner for Trap5Trig {
             offset: Offset.sample(rng),
         }
     }
-    fn embed(&self, v: Vector2<f64>) -> V4 {
-        emb_5() * v + self.offset
+    fn embed(&self, v: Vector2<f64>, shift: V4) -> V4 {
+        emb_5() * v + self.offset + shift
     }
     fn iterate(&self, v: V4) -> V4 {
         let ex = Complex64::from_polar(1.0, v.x);
@@ -211,9 +231,25 @@ impl TrapRunner for Trap5Trig {
     fn dist(&self, p: V4) -> f64 {
         dist_5(p)
     }
+    fn angle(&self, p: V4) -> f64 {
+        angle_5(p)
+    }
     fn num_iters(&self) -> usize {
         15
     }
+    fn mix(&self, other: &Self, t: f64) -> Self {
+        Self {
+            a0: mix(self.a0, other.a0, t),
+            a1: pick(self.a1, other.a1, t),
+            a3: mix(self.a3, other.a3, t),
+            a4: mix(self.a4, other.a4, t),
+            a5: mix(self.a5, other.a5, t),
+            a6: mix(self.a6, other.a6, t),
+            a7: mix(self.a7, other.a7, t),
+            flip: pick(self.flip, other.flip, t),
+            offset: mix_offset(&self.offset, &other.offset, t),
+        }
+    }
 }
 
 struct Trap10Trig {
@@ -244,8 +280,8 @@ impl TrapRunner for Trap10Trig {
             offset: Offset.sample(rng),
         }
     }
-    fn embed(&self, v: Vector2<f64>) -> V4 {
-        emb_5() * v + self.offset
+    fn embed(&self, v: Vector2<f64>, shift: V4) -> V4 {
+        emb_5() * v + self.offset + shift
     }
     fn iterate(&self, v: V4) -> V4 {
         let ex = v.x.sin();
@@ -265,9 +301,24 @@ impl TrapRunner for Trap10Trig {
     fn dist(&self, p: V4) -> f64 {
         dist_5(p)
     }
+    fn angle(&self, p: V4) -> f64 {
+        angle_5(p)
+    }
     fn num_iters(&self) -> usize {
         15
     }
+    fn mix(&self, other: &Self, t: f64) -> Self {
+        Self {
+            a1: pick(self.a1, other.a1, t),
+            a3: mix(self.a3, other.a3, t),
+            a4: mix(self.a4, other.a4, t),
+            a5: mix(self.a5, other.a5, t),
+            a6: mix(self.a6, other.a6, t),
+            a7: mix(self.a7, other.a7, t),
+            flip: pick(self.flip, other.flip, t),
+            offset: mix_offset(&self.offset, &other.offset, t),
+        }
+    }
 }
 
 struct Trap8Trig {
@@ -298,8 +349,8 @@ impl TrapRunner for Trap8Trig {
             offset: Offset.sample(rng),
         }
     }
-    fn embed(&self, v: Vector2<f64>) -> V4 {
-        emb_8() * v + self.offset
+    fn embed(&self, v: Vector2<f64>, shift: V4) -> V4 {
+        emb_8() * v + self.offset + shift
     }
     fn iterate(&self, v: V4) -> V4 {
         let ex = v.x.sin();
@@ -316,9 +367,24 @@ impl TrapRunner for Trap8Trig {
     fn dist(&self, p: V4) -> f64 {
         dist_8(p)
     }
+    fn angle(&self, p: V4) -> f64 {
+        angle_8(p)
+    }
     fn num_iters(&self) -> usize {
         15
     }
+    fn mix(&self, other: &Self, t: f64) -> Self {
+        Self {
+            a0: mix(self.a0, other.a0, t),
+            a1: pick(self.a1, other.a1, t),
+            a3: mix(self.a3, other.a3, t),
+            a4: mix(self.a4, other.a4, t),
+            a5: mix(self.a5, other.a5, t),
+            a6: mix(self.a6, other.a6, t),
+            flip: pick(self.flip, other.flip, t),
+            offset: mix_offset(&self.offset, &other.offset, t),
+        }
+    }
 }
 
 struct Trap12Trig {
@@ -351,8 +417,8 @@ impl TrapRunner for Trap12Trig {
             offset: Offset.sample(rng),
         }
     }
-    fn embed(&self, v: Vector2<f64>) -> V4 {
-        emb_12() * v + self.offset
+    fn embed(&self, v: Vector2<f64>, shift: V4) -> V4 {
+        emb_12() * v + self.offset + shift
     }
     fn iterate(&self, v: V4) -> V4 {
         let v6 = Vector6::new(
@@ -378,9 +444,25 @@ impl TrapRunner for Trap12Trig {
     fn dist(&self, p: V4) -> f64 {
         dist_12(p)
     }
+    fn angle(&self, p: V4) -> f64 {
+        angle_12(p)
+    }
     fn num_iters(&self) -> usize {
         15
     }
+    fn mix(&self, other: &Self, t: f64) -> Self {
+        Self {
+            a1: pick(self.a1, other.a1, t),
+            a3: mix(self.a3, other.a3, t),
+            a4: mix(self.a4, other.a4, t),
+            a5: mix(self.a5, other.a5, t),
+            a6: mix(self.a6, other.a6, t),
+            a7: mix(self.a7, other.a7, t),
+            a8: mix(self.a8, other.a8, t),
+            flip: pick(self.flip, other.flip, t),
+            offset: mix_offset(&self.offset, &other.offset, t),
+        }
+    }
 }
 
 fn emb_5() -> Matrix4x2<f64> {
@@ -435,6 +517,27 @@ fn dist_12(v: V4) -> f64 {
         * (v.x.cos() + v.y.cos() + v.z.cos() + v.w.cos() + (v.x - v.z).cos() + (v.y - v.w).cos())
 }
 
+fn phase_sum(phases: &[f64]) -> Complex64 {
+    phases.iter().map(|&q| Complex64::from_polar(1.0, q)).sum()
+}
+
+fn angle_5(v: V4) -> f64 {
+    phase_sum(&[v.x, v.y, v.z, v.w, -(v.x + v.y + v.z + v.w)]).arg()
+}
+
+fn angle_8(v: V4) -> f64 {
+    phase_sum(&[v.x, v.y, v.z, v.w]).arg()
+}
+
+fn angle_12(v: V4) -> f64 {
+    phase_sum(&[v.x, v.y, v.z, v.w, v.x - v.z, v.y - v.w]).arg()
+}
+
+/// Blends each phase of the offset the short way around.
+fn mix_offset(a: &V4, b: &V4, t: f64) -> V4 {
+    a.zip_map(b, |a, b| mix_angle(a, b, t))
+}
+
 struct Offset;
 
 impl Distribution<V4> for Offset {
@@ -444,23 +547,92 @@ impl Distribution<V4> for Offset {
     }
 }
 
+/// A whole number of turns for each phase of the offset, so that drifting
+/// the offset by `2π` times the winding brings the pattern back to the
+/// start.
+struct Winding;
+
+impl Distribution<V4> for Winding {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> V4 {
+        let turns = Uniform::new_inclusive(-1, 1);
+        loop {
+            let mut turn = || turns.sample(rng) as f64;
+            let v = V4::new(turn(), turn(), turn(), turn());
+            if v != V4::zeros() {
+                return v;
+            }
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum QuasitrapError {
     #[error("Bad parameters")]
     BadParam,
 }
 
+/// Which properties of the orbit determine the color of a pixel.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Display,
+    EnumIter,
+    EnumString,
+)]
+pub enum Coloring {
+    /// The trap distance is passed through the colormap.
+    #[default]
+    Distance,
+    /// The final orbit angle is passed through the colormap.
+    Angle,
+    /// The orbit angle selects a color from the colormap and the trap
+    /// distance sets its brightness.
+    DistanceAngle,
+}
+
 #[derive(Deserialize)]
 pub struct Quasitrap {
     pub symmetries: u8,
     pub quasiperiod: f64,
     pub height: u32,
     pub width: u32,
-}
-
-fn make_runner<T: TrapRunner + 'static>() -> Box<dyn Fn(V2) -> u8> {
+    #[serde(default)]
+    pub colormap: Colormap,
+    #[serde(default)]
+    pub coloring: Coloring,
+    #[serde(default)]
+    pub palette: Palette,
+    /// Samples per pixel, which must be a square.
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+    #[serde(default)]
+    pub sample_pattern: SamplePattern,
+    #[serde(default)]
+    pub pixel_filter: PixelFilter,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub quality: Quality,
+}
+
+fn default_samples() -> u32 {
+    1
+}
+
+type Runner = Box<dyn Fn(V2, V4) -> TrapValue + Send + Sync>;
+
+/// A preview follows the whole orbit, since stopping it early changes the
+/// image, so it only saves time through its resolution and samples.
+fn make_runner<T: TrapRunner + Send + Sync + 'static>() -> Runner {
     let runner = symart_base::rng::sample_fn(|r| T::new_random(r));
-    let f = move |v| runner.run(v);
+    let iters = runner.num_iters();
+    let f = move |v, shift| runner.run(v, shift, iters);
     Box::new(f)
 }
 
@@ -487,30 +659,199 @@ impl symart_base::Design for Quasitrap {
                     "default": 100
                 },
                 "height": schema::height(),
-                "width": schema::width()
+                "width": schema::width(),
+                "colormap": schema::colormap(),
+                "coloring": {
+                    "type": "string",
+                    "title": "Coloring",
+                    "enum": schema::enum_strings::<Coloring>(),
+                    "default": "Distance"
+                },
+                "palette": schema::palette(),
+                "samples": schema::samples(),
+                "sample_pattern": schema::sample_pattern(),
+                "pixel_filter": schema::pixel_filter(),
+                "seed": schema::seed(),
+                "quality": schema::quality()
             },
             "required": ["symmetries", "quasiperiod"]
         })
     }
 
     fn draw(&self) -> Result<DrawResponse, Box<dyn std::error::Error>> {
+        let pixel_fn = self.pixel_fn()?;
+        let (width, height) = self.size();
+        let im = self.sampler().render(width, height, pixel_fn);
+        Ok(DrawResponse {
+            im: im.into(),
+            sym: symart_base::SymmetryType::None,
+        })
+    }
+
+    fn dimensions(&self) -> Option<(u32, u32)> {
+        Some(self.size())
+    }
+}
+
+type Evaluator = Box<dyn Fn(f64) -> [f64; 3] + Send + Sync>;
+
+fn trap_color(tv: TrapValue, cmap: &Evaluator, coloring: Coloring) -> [f64; 3] {
+    let dist = 0.5 * (tv.dist + 1.0);
+    let angle = (tv.angle / (2. * PI)).rem_euclid(1.0);
+    match coloring {
+        Coloring::Distance => cmap(dist),
+        Coloring::Angle => cmap(angle),
+        Coloring::DistanceAngle => cmap(angle).map(|v| v * dist),
+    }
+}
+
+/// Returns the pixel function at each time of a morph from `a` to `b`.
+/// Each end makes the same random choices as a still render with its seed.
+fn morph_pixel_fn<T: TrapRunner + Send + Sync + 'static>(
+    a: &Quasitrap,
+    b: &Quasitrap,
+) -> Box<dyn Fn(f64) -> PixelFn + Send + Sync> {
+    let choose = |q: &Quasitrap| {
+        symart_base::rng::with_optional_seed(q.seed, || {
+            let runner = symart_base::rng::sample_fn(|r| T::new_random(r));
+            (runner, Arc::new(q.colormap.evaluator(&q.palette)))
+        })
+    };
+    let (runner_a, cmap_a) = choose(a);
+    let (runner_b, cmap_b) = choose(b);
+    let iters = runner_a.num_iters();
+    let (factor_a, factor_b) = (a.factor(), b.factor());
+    let (coloring_a, coloring_b) = (a.coloring, b.coloring);
+    Box::new(move |t| {
+        let runner = runner_a.mix(&runner_b, t);
+        let factor = mix(factor_a, factor_b, t);
+        let (cmap_a, cmap_b) = (cmap_a.clone(), cmap_b.clone());
+        Box::new(move |x, y| {
+            let tv = runner.run(factor * V2::new(x, y), V4::zeros(), iters);
+            let ca = trap_color(tv, &cmap_a, coloring_a);
+            let cb = trap_color(tv, &cmap_b, coloring_b);
+            [0, 1, 2].map(|i| mix(ca[i], cb[i], t))
+        })
+    })
+}
+
+type ShiftedPixelFn = Box<dyn Fn(f64, f64, V4) -> [f64; 3] + Send + Sync>;
+
+impl Quasitrap {
+    /// The factor by which the image is shrunk.  A preview shows the same
+    /// region of the plane with fewer pixels.
+    fn scale(&self) -> f64 {
+        self.quality.scale(self.width, self.height)
+    }
+
+    /// The color at a point when the offset of the orbit is shifted.
+    fn shifted_pixel_fn(&self) -> Result<ShiftedPixelFn, QuasitrapError> {
+        samples_per_axis(self.samples).ok_or(QuasitrapError::BadParam)?;
         let runner = match self.symmetries {
             5 => make_runner::<Trap5Trig>(),
             8 => make_runner::<Trap8Trig>(),
             10 => make_runner::<Trap10Trig>(),
             12 => make_runner::<Trap12Trig>(),
+            _ => return Err(QuasitrapError::BadParam),
+        };
+        let cmap = self.colormap.evaluator(&self.palette);
+        let factor = self.factor();
+        let coloring = self.coloring;
+        Ok(Box::new(move |x, y, shift| {
+            let v2 = factor * V2::new(x, y);
+            trap_color(runner(v2, shift), &cmap, coloring)
+        }))
+    }
+
+    /// The scale from pixels to the coordinates of the orbit.
+    fn factor(&self) -> f64 {
+        2. * PI / (self.quasiperiod * self.scale())
+    }
+}
+
+impl PixelDesign for Quasitrap {
+    fn size(&self) -> (u32, u32) {
+        let k = self.scale();
+        let shrink = |n: u32| (((n as f64) * k).round() as u32).max(1);
+        (shrink(self.width), shrink(self.height))
+    }
+
+    /// A number of samples that is not a square is rejected by `pixel_fn`.
+    fn sampler(&self) -> Supersampler {
+        let samples = match self.quality {
+            Quality::Full => samples_per_axis(self.samples).unwrap_or(1),
+            Quality::Preview => 1,
+        };
+        Supersampler::new(samples, self.sample_pattern, self.pixel_filter)
+    }
+
+    fn pixel_fn(&self) -> Result<PixelFn, Box<dyn std::error::Error>> {
+        let f = symart_base::rng::with_optional_seed(self.seed, || self.shifted_pixel_fn())?;
+        Ok(Box::new(move |x, y| f(x, y, V4::zeros())))
+    }
+}
+
+impl Animate for Quasitrap {
+    /// The offset of the orbit drifts by whole turns, so the animation
+    /// loops.
+    fn animation(&self) -> Result<Animation, Box<dyn std::error::Error>> {
+        let (f, winding) = symart_base::rng::with_optional_seed(self.seed, || {
+            let f = self.shifted_pixel_fn()?;
+            Ok::<_, QuasitrapError>((f, symart_base::rng::sample(Winding)))
+        })?;
+        let (width, height) = self.size();
+        let sampler = self.sampler();
+        let frame = move |t: f64| {
+            let shift = 2. * PI * t * winding;
+            let im = sampler.render(width, height, |x, y| f(x, y, shift));
+            DrawResponse {
+                im: im.into(),
+                sym: symart_base::SymmetryType::None,
+            }
+        };
+        Ok(Animation {
+            frame: Box::new(frame),
+            periodic: true,
+        })
+    }
+}
+
+impl Morph for Quasitrap {
+    /// Both designs must have the same number of symmetries, size and
+    /// quality.  The coefficients and colors are blended, but the transforms
+    /// of the orbit cannot be, so the image jumps halfway through, where the
+    /// transforms of `self` are replaced by those of `other`, unless the two
+    /// have the same ones.
+    fn morph(&self, other: &Self) -> Result<Animation, Box<dyn std::error::Error>> {
+        if self.symmetries != other.symmetries {
+            return Err(Box::new(MorphError::Mismatch("symmetries")));
+        }
+        if (self.width, self.height) != (other.width, other.height) {
+            return Err(Box::new(MorphError::Mismatch("size")));
+        }
+        if self.quality != other.quality {
+            return Err(Box::new(MorphError::Mismatch("quality")));
+        }
+        samples_per_axis(self.samples).ok_or(QuasitrapError::BadParam)?;
+        let pixel_fn = match self.symmetries {
+            5 => morph_pixel_fn::<Trap5Trig>(self, other),
+            8 => morph_pixel_fn::<Trap8Trig>(self, other),
+            10 => morph_pixel_fn::<Trap10Trig>(self, other),
+            12 => morph_pixel_fn::<Trap12Trig>(self, other),
             _ => return Err(Box::new(QuasitrapError::BadParam)),
         };
-        let factor = 2. * PI / self.quasiperiod;
-        let pixel_fn = move |x, y| {
-            let v2 = factor * V2::new(x as f64, y as f64);
-            let v = runner(v2);
-            image::Rgb([v, v, v])
+        let (width, height) = self.size();
+        let sampler = self.sampler();
+        let frame = move |t: f64| {
+            let im = sampler.render(width, height, pixel_fn(t));
+            DrawResponse {
+                im: im.into(),
+                sym: symart_base::SymmetryType::None,
+            }
         };
-        let im = RgbImage::from_fn(self.width, self.height, pixel_fn);
-        Ok(DrawResponse {
-            im,
-            sym: symart_base::SymmetryType::None,
+        Ok(Animation {
+            frame: Box::new(frame),
+            periodic: false,
         })
     }
 }
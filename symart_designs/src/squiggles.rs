@@ -1,93 +1,254 @@
-use image::RgbImage;
-use ndarray::{indices_of, Array2};
-use num_complex::Complex64;
-use rand::distributions::Distribution;
-use rand::Rng;
-use rustfft::FftPlanner;
+use ndarray::{Array2, Zip};
+use num_complex::Complex;
 use std::f64::consts::PI;
-use symart_base::canvas::{Coord, WrapCanvas, WrapDimension};
-use symart_base::fft::Plan2D;
-use symart_base::random::Levy;
-use symart_base::rng::sample_fn;
+use symart_base::animate::{mix, pick, Animation, MorphError};
+use symart_base::canvas::WrapCanvas;
+use symart_base::color::{Background, Palette};
+use symart_base::fft::{FftFloat, Plan2D, Precision};
+use symart_base::field::{
+    apply_filter, octave_sum, random_field, random_real_field, white_noise, FieldParams, Octaves,
+    SpectralFilter,
+};
+use symart_base::layer::{Layer, LayeredImage, Paint, Shading};
+use symart_base::memory;
+use symart_base::rng::{derive_seed, next_seed, seeded_rng, with_optional_seed};
 use symart_base::symmetric_canvas::SymmetricCanvas;
-use symart_base::symmetry::{transformations, SymmetryGroup};
-use symart_base::{make_layers_n, schema, DrawResponse, SymmetryChoice};
+use symart_base::symmetry::{GridNorm, SymmetryGroup};
+use symart_base::{
+    make_layers_n_sized, schema, Animate, Design, DrawResponse, LayeredDesign, Morph, Quality,
+    SymmetryChoice,
+};
 
-#[derive(Clone)]
-pub struct SquigglesParam {
-    pub exponent: f64,
-    pub alpha: f64,
-    pub thickness: f64,
-    pub sharpness: f64,
+/// How the layers are drawn from each field.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SquiggleStyle {
+    /// A band around the zero level set, which is wider where the field is
+    /// flat.
+    #[default]
+    Squiggles,
+    /// `levels` level sets, `spacing` standard deviations apart and
+    /// centered on zero, drawn as lines `width` pixels wide.  With
+    /// `separate_colors`, each level is a layer of its own.
+    Contours {
+        levels: usize,
+        spacing: f64,
+        width: f64,
+        separate_colors: bool,
+    },
+    /// `bands` regions separated by level sets, each above the lowest a
+    /// layer of its own.  The edges blend over `softness` standard
+    /// deviations of the field, or are sharp if it is 0.  The levels split
+    /// the field into equal areas with `equal_areas`, and are otherwise
+    /// `spacing` standard deviations apart and centered on zero.  The bottom
+    /// layer covers the whole image, and gives the lowest region of the
+    /// first field its color.
+    Regions {
+        bands: usize,
+        equal_areas: bool,
+        spacing: f64,
+        softness: f64,
+    },
 }
 
-fn generate_single<R: Rng + ?Sized>(rng: &mut R, alpha: f64) -> Complex64 {
-    Complex64::new(Levy { alpha }.sample(rng), 0.)
+impl SquiggleStyle {
+    /// The number of layers drawn from each part of a field.
+    pub fn layers_per_part(&self) -> usize {
+        match self {
+            SquiggleStyle::Contours {
+                levels,
+                separate_colors: true,
+                ..
+            } => (*levels).max(1),
+            SquiggleStyle::Regions { bands, .. } => (*bands).max(1) - 1,
+            _ => 1,
+        }
+    }
+
+    /// The style a fraction `t` of the way to `other`.  Styles of different
+    /// kinds switch halfway.
+    pub fn mix(&self, other: &SquiggleStyle, t: f64) -> SquiggleStyle {
+        match (self, other) {
+            (
+                SquiggleStyle::Contours {
+                    levels: l1,
+                    spacing: s1,
+                    width: w1,
+                    separate_colors: c1,
+                },
+                SquiggleStyle::Contours {
+                    levels: l2,
+                    spacing: s2,
+                    width: w2,
+                    separate_colors: c2,
+                },
+            ) => SquiggleStyle::Contours {
+                levels: pick(*l1, *l2, t),
+                spacing: mix(*s1, *s2, t),
+                width: mix(*w1, *w2, t),
+                separate_colors: pick(*c1, *c2, t),
+            },
+            (
+                SquiggleStyle::Regions {
+                    bands: b1,
+                    equal_areas: e1,
+                    spacing: s1,
+                    softness: f1,
+                },
+                SquiggleStyle::Regions {
+                    bands: b2,
+                    equal_areas: e2,
+                    spacing: s2,
+                    softness: f2,
+                },
+            ) => SquiggleStyle::Regions {
+                bands: pick(*b1, *b2, t),
+                equal_areas: pick(*e1, *e2, t),
+                spacing: mix(*s1, *s2, t),
+                softness: mix(*f1, *f2, t),
+            },
+            _ => pick(self, other, t).clone(),
+        }
+    }
 }
 
-fn generate_double<R: Rng + ?Sized>(rng: &mut R, alpha: f64) -> Complex64 {
-    Complex64::new(Levy { alpha }.sample(rng), Levy { alpha }.sample(rng))
+fn style_schema() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Style",
+        "oneOf": [
+            {
+                "title": "Squiggles",
+                "type": "object",
+                "properties": { "type": { "const": "Squiggles" } },
+                "required": ["type"]
+            },
+            {
+                "title": "Contours",
+                "type": "object",
+                "properties": {
+                    "type": { "const": "Contours" },
+                    "levels": {
+                        "type": "integer",
+                        "title": "Levels",
+                        "minimum": 1,
+                        "maximum": 64,
+                        "default": 8
+                    },
+                    "spacing": {
+                        "type": "number",
+                        "title": "Spacing",
+                        "minimum": 0,
+                        "default": 0.5
+                    },
+                    "width": {
+                        "type": "number",
+                        "title": "Line width",
+                        "minimum": 0,
+                        "default": 2
+                    },
+                    "separate_colors": {
+                        "type": "boolean",
+                        "title": "Color each level",
+                        "default": false
+                    }
+                },
+                "required": ["type", "levels", "spacing", "width", "separate_colors"]
+            },
+            {
+                "title": "Regions",
+                "type": "object",
+                "properties": {
+                    "type": { "const": "Regions" },
+                    "bands": {
+                        "type": "integer",
+                        "title": "Bands",
+                        "minimum": 1,
+                        "maximum": 64,
+                        "default": 2
+                    },
+                    "equal_areas": {
+                        "type": "boolean",
+                        "title": "Equal areas",
+                        "default": false
+                    },
+                    "spacing": {
+                        "type": "number",
+                        "title": "Spacing",
+                        "minimum": 0,
+                        "default": 0.5
+                    },
+                    "softness": {
+                        "type": "number",
+                        "title": "Edge softness",
+                        "minimum": 0,
+                        "default": 0
+                    }
+                },
+                "required": ["type", "bands", "equal_areas", "spacing", "softness"]
+            }
+        ],
+        "default": { "type": "Squiggles" }
+    })
 }
 
-fn generate_noise<R: Rng + ?Sized>(
-    plan: &Plan2D,
-    rng: &mut R,
-    alpha: f64,
-    single: bool,
-) -> Array2<Complex64> {
-    let gen = if single {
-        generate_single
-    } else {
-        generate_double
-    };
-    Array2::from_shape_fn((plan.width(), plan.height()), |_| gen(rng, alpha))
+#[derive(Clone)]
+pub struct SquigglesParam {
+    pub style: SquiggleStyle,
+    pub filter: SpectralFilter,
+    pub exponent: f64,
+    pub alpha: f64,
+    pub octaves: Octaves,
+    pub thickness: f64,
+    pub sharpness: f64,
+    pub precision: Precision,
+    /// The `reduction` of the fields, as in `FieldParams`.
+    pub reduction: usize,
 }
 
-fn generate_noise_symmetric<R: Rng + ?Sized>(
-    plan: &Plan2D,
-    rng: &mut R,
-    alpha: f64,
-    single: bool,
-    sym: SymmetryGroup,
-) -> Array2<Complex64> {
-    let gen = if single {
-        generate_single
-    } else {
-        generate_double
-    };
-    let mut arr = Array2::zeros((plan.width(), plan.height()));
-    let dims = WrapDimension::new_from_shape(arr.shape());
-    let transforms = transformations(sym, (plan.width() / 2) as i32);
-    for (x, y) in indices_of(&arr) {
-        let pt = Coord::new(x as i32, y as i32);
-        let v = gen(rng, alpha);
-        for t in &transforms {
-            arr[dims.compute_index(&t.apply(&pt))] += v;
+impl SquigglesParam {
+    /// The field of a pair of layers, or of a single layer if `single` is
+    /// set.
+    pub fn field(&self, symmetry: Option<SymmetryGroup>, single: bool) -> FieldParams {
+        FieldParams {
+            filter: self.filter.clone(),
+            exponent: self.exponent,
+            alpha: self.alpha,
+            symmetry,
+            lattice: symmetry.map_or(GridNorm::Square, GridNorm::from_symmetry),
+            frequency: 1.,
+            complex: !single,
+            reduction: self.reduction,
         }
     }
-    arr
 }
 
-fn convolve(plan: &Plan2D, arr: &mut Array2<Complex64>, exponent: f64) {
-    plan.apply(arr);
-    let ax = 2. * PI / (plan.width() as f64);
-    let ay = 2. * PI / (plan.height() as f64);
-    let c = 3. - f64::cos(f64::min(ax, ay));
-    for ((x, y), d) in arr.indexed_iter_mut() {
-        let r = c - f64::cos(ax * (x as f64)) - f64::cos(ay * (y as f64));
-        *d *= r.powf(-exponent / 2.0);
-    }
-    plan.apply(arr);
+/// The sum of the octaves of a complex field.
+fn octave_field<T: FftFloat>(
+    plan: &Plan2D<T>,
+    octaves: &Octaves,
+    field: &FieldParams,
+) -> Array2<Complex<T>> {
+    let octaves = octaves.octaves(field);
+    octave_sum(octaves.iter().map(|(f, w)| (random_field(plan, f), *w)))
+}
+
+/// The sum of the octaves of a real field.
+fn octave_real_field<T: FftFloat>(
+    plan: &Plan2D<T>,
+    octaves: &Octaves,
+    field: &FieldParams,
+) -> Array2<T> {
+    let octaves = octaves.octaves(field);
+    octave_sum(
+        octaves
+            .iter()
+            .map(|(f, w)| (random_real_field(plan, f), *w)),
+    )
 }
 
-fn make_squiggles<F>(
-    arr: &Array2<Complex64>,
-    mut proj: F,
-    thickness: f64,
-    sharpness: f64,
-) -> Array2<u8>
+fn make_squiggles<A, F>(arr: &Array2<A>, mut proj: F, thickness: f64, sharpness: f64) -> Array2<u16>
 where
-    F: FnMut(&Complex64) -> f64,
+    F: FnMut(&A) -> f64,
 {
     let n2: f64 = arr.iter().map(&mut proj).map(|x| x * x).sum();
     let norm = 6.4 / (thickness * (n2 / (arr.len() as f64)).sqrt());
@@ -96,99 +257,424 @@ where
         arr.iter()
             .map(|p| {
                 let height = (proj(p) * norm).abs();
-                (255.99 / (height.powf(sharpness) + 1.)) as u8
+                (65535.99 / (height.powf(sharpness) + 1.)) as u16
             })
             .collect(),
     )
     .unwrap()
 }
 
-fn make_squiggles_symmetric<F>(
-    arr: &Array2<Complex64>,
+/// The signed distance in pixels from a point to the level set of `v` at
+/// `level`, estimated from the gradient.  On a hexagonal grid the axes are
+/// at 60 degrees, and the gradient is fit by least squares to the
+/// differences along all three directions of neighbours, so that the
+/// distance is the same at every point of an orbit.
+fn level_distance(v: &Array2<f64>, norm: GridNorm, (x, y): (usize, usize), level: f64) -> f64 {
+    let (w, h) = v.dim();
+    let (xp, xm) = ((x + 1) % w, (x + w - 1) % w);
+    let (yp, ym) = ((y + 1) % h, (y + h - 1) % h);
+    let gx = 0.5 * (v[(xp, y)] - v[(xm, y)]);
+    let gy = 0.5 * (v[(x, yp)] - v[(x, ym)]);
+    let g = match norm {
+        GridNorm::Square => gx.hypot(gy),
+        GridNorm::Hexagonal => {
+            let gz = 0.5 * (v[(xp, ym)] - v[(xm, yp)]);
+            let q = gx * gx + gy * gy + gz * gz + gx * gy + gx * gz - gy * gz;
+            (4. / 9. * q).sqrt()
+        }
+    };
+    (v[(x, y)] - level) / g.max(f64::MIN_POSITIVE)
+}
+
+/// A ramp from 0 to 1 with a continuous slope.
+fn smoothstep(c: f64) -> f64 {
+    let c = c.clamp(0., 1.);
+    c * c * (3. - 2. * c)
+}
+
+fn rms(v: &Array2<f64>) -> f64 {
+    (v.iter().map(|x| x * x).sum::<f64>() / (v.len() as f64)).sqrt()
+}
+
+fn to_mask(c: f64) -> u16 {
+    (65535.99 * c) as u16
+}
+
+/// Antialiased lines along the level sets.  The distance in pixels to a
+/// level is estimated from the gradient, so the lines have the same width
+/// everywhere.
+fn make_contours<A, F>(
+    arr: &Array2<A>,
     proj: F,
-    thickness: f64,
-    sharpness: f64,
+    norm: GridNorm,
+    levels: usize,
+    spacing: f64,
+    width: f64,
+    separate: bool,
+) -> Vec<Array2<u16>>
+where
+    F: FnMut(&A) -> f64,
+{
+    let v = arr.map(proj);
+    let rms = rms(&v);
+    let levels = levels.max(1);
+    let level = |j: usize| (j as f64 - 0.5 * ((levels - 1) as f64)) * spacing * rms;
+    let coverage = |i: (usize, usize), j: usize| {
+        smoothstep(0.5 * width + 0.5 - level_distance(&v, norm, i, level(j)).abs())
+    };
+    if separate {
+        (0..levels)
+            .map(|j| Array2::from_shape_fn(v.dim(), |i| to_mask(coverage(i, j))))
+            .collect()
+    } else {
+        let mask = Array2::from_shape_fn(v.dim(), |i| {
+            to_mask((0..levels).map(|j| coverage(i, j)).fold(0., f64::max))
+        });
+        vec![mask]
+    }
+}
+
+/// A mask for each region above the lowest, which covers the points above
+/// the level set below the region.  Each mask covers the ones after it, so
+/// the layers are painted over each other in order.
+fn make_regions<A, F>(
+    arr: &Array2<A>,
+    proj: F,
+    norm: GridNorm,
+    bands: usize,
+    equal_areas: bool,
+    spacing: f64,
+    softness: f64,
+) -> Vec<Array2<u16>>
+where
+    F: FnMut(&A) -> f64,
+{
+    let v = arr.map(proj);
+    let (n, rms) = (bands.max(1), rms(&v));
+    let levels: Vec<f64> = if equal_areas {
+        let mut sorted: Vec<f64> = v.iter().copied().collect();
+        sorted.sort_unstable_by(f64::total_cmp);
+        (1..n).map(|j| sorted[j * sorted.len() / n]).collect()
+    } else {
+        (1..n)
+            .map(|j| (j as f64 - 0.5 * (n as f64)) * spacing * rms)
+            .collect()
+    };
+    let coverage = |i: (usize, usize), l: f64| {
+        if softness > 0. {
+            1. / (1. + f64::exp((l - v[i]) / (softness * rms)))
+        } else {
+            smoothstep(level_distance(&v, norm, i, l) + 0.5)
+        }
+    };
+    levels
+        .into_iter()
+        .map(|l| Array2::from_shape_fn(v.dim(), |i| to_mask(coverage(i, l))))
+        .collect()
+}
+
+/// The masks of the layers drawn from one part of a field on the grid with
+/// the given norm.
+fn part_masks<A, F>(
+    arr: &Array2<A>,
+    proj: F,
+    norm: GridNorm,
+    param: &SquigglesParam,
+) -> Vec<Array2<u16>>
+where
+    F: FnMut(&A) -> f64,
+{
+    match param.style {
+        SquiggleStyle::Squiggles => {
+            vec![make_squiggles(arr, proj, param.thickness, param.sharpness)]
+        }
+        SquiggleStyle::Contours {
+            levels,
+            spacing,
+            width,
+            separate_colors,
+        } => {
+            let width = width / (param.reduction as f64);
+            make_contours(arr, proj, norm, levels, spacing, width, separate_colors)
+        }
+        SquiggleStyle::Regions {
+            bands,
+            equal_areas,
+            spacing,
+            softness,
+        } => make_regions(arr, proj, norm, bands, equal_areas, spacing, softness),
+    }
+}
+
+fn part_masks_symmetric<A, F>(
+    arr: &Array2<A>,
+    proj: F,
+    param: &SquigglesParam,
     sg: SymmetryGroup,
-) -> SymmetricCanvas<u8>
+) -> Vec<SymmetricCanvas<u16>>
 where
-    F: FnMut(&Complex64) -> f64,
+    F: FnMut(&A) -> f64,
 {
-    let wc: WrapCanvas<u8> = make_squiggles(arr, proj, thickness, sharpness).into();
-    SymmetricCanvas::from_wrap_canvas(wc, sg)
+    part_masks(arr, proj, GridNorm::from_symmetry(sg), param)
+        .into_iter()
+        .map(|m| SymmetricCanvas::from_wrap_canvas(WrapCanvas::from(m), sg))
+        .collect()
 }
 
-fn proj_re(c: &Complex64) -> f64 {
-    c.re
+fn proj_re<T: FftFloat>(c: &Complex<T>) -> f64 {
+    c.re.to_f64().unwrap()
 }
 
-fn proj_im(c: &Complex64) -> f64 {
-    c.im
+fn proj_im<T: FftFloat>(c: &Complex<T>) -> f64 {
+    c.im.to_f64().unwrap()
 }
 
-pub fn generate_squiggles(plan: &Plan2D, param: &SquigglesParam, single: bool) -> Vec<Array2<u8>> {
-    let mut arr = sample_fn(|rng| generate_noise(plan, rng, param.alpha, single));
-    convolve(plan, &mut arr, param.exponent);
-    let n = if single { 1 } else { 2 };
-    [proj_re, proj_im][..n]
+fn proj_real<T: FftFloat>(v: &T) -> f64 {
+    v.to_f64().unwrap()
+}
+
+pub fn generate_squiggles<T: FftFloat>(
+    plan: &Plan2D<T>,
+    param: &SquigglesParam,
+    single: bool,
+) -> Vec<Array2<u16>> {
+    let field = FieldParams {
+        reduction: 1,
+        ..param.field(None, single)
+    };
+    if single {
+        let arr = octave_real_field(plan, &param.octaves, &field);
+        return part_masks(&arr, proj_real, field.lattice, param);
+    }
+    let arr = octave_field(plan, &param.octaves, &field);
+    [proj_re::<T>, proj_im::<T>]
         .iter()
-        .map(|f| make_squiggles(&arr, f, param.thickness, param.sharpness))
+        .flat_map(|f| part_masks(&arr, f, field.lattice, param))
         .collect()
 }
 
-pub fn generate_squiggles_symmetric(
+/// The filtered noise of a pair of layers.  Its real and imaginary parts
+/// give the two layers, or just the real part if `single` is set.
+pub fn generate_field_symmetric<T: FftFloat>(
+    sym: SymmetryGroup,
+    plan: &Plan2D<T>,
+    param: &SquigglesParam,
+    single: bool,
+) -> Array2<Complex<T>> {
+    octave_field(plan, &param.octaves, &param.field(Some(sym), single))
+}
+
+pub fn field_squiggles_symmetric<T: FftFloat>(
     sym: SymmetryGroup,
-    plan: &Plan2D,
+    arr: &Array2<Complex<T>>,
     param: &SquigglesParam,
     single: bool,
-) -> Vec<SymmetricCanvas<u8>> {
-    let mut arr = sample_fn(|rng| generate_noise_symmetric(plan, rng, param.alpha, single, sym));
-    convolve(plan, &mut arr, param.exponent);
+) -> Vec<SymmetricCanvas<u16>> {
     let n = if single { 1 } else { 2 };
-    [proj_re, proj_im][..n]
+    [proj_re::<T>, proj_im::<T>][..n]
         .iter()
-        .map(|f| make_squiggles_symmetric(&arr, f, param.thickness, param.sharpness, sym))
+        .flat_map(|f| part_masks_symmetric(arr, f, param, sym))
         .collect()
 }
 
-pub fn squiggles_layers(
+/// Like `field_squiggles_symmetric` of `generate_field_symmetric`, but a
+/// single layer is drawn from a real field, with real transforms.
+pub fn generate_squiggles_symmetric<T: FftFloat>(
+    sym: SymmetryGroup,
+    plan: &Plan2D<T>,
+    param: &SquigglesParam,
+    single: bool,
+) -> Vec<SymmetricCanvas<u16>> {
+    if single {
+        let arr = octave_real_field(plan, &param.octaves, &param.field(Some(sym), true));
+        return part_masks_symmetric(&arr, proj_real, param, sym);
+    }
+    let arr = generate_field_symmetric(sym, plan, param, single);
+    field_squiggles_symmetric(sym, &arr, param, single)
+}
+
+/// The bytes used while generating a pair of layers: the complex noise, in
+/// the precision of the parameters, and the masks of both parts.  Several
+/// octaves are drawn one at a time, and need a second field for the sum.
+/// Contours and regions also keep one part of the field in double
+/// precision, and regions with equal areas a sorted copy of it.
+pub fn squiggles_pair_bytes(width: usize, height: usize, param: &SquigglesParam) -> usize {
+    let style = &param.style;
+    let fields = 1 + (param.octaves.num_octaves() > 1) as usize;
+    let masks = 2 * style.layers_per_part() * std::mem::size_of::<u16>();
+    let part = match style {
+        SquiggleStyle::Squiggles => 0,
+        SquiggleStyle::Contours { .. } => std::mem::size_of::<f64>(),
+        SquiggleStyle::Regions { equal_areas, .. } => {
+            (1 + *equal_areas as usize) * std::mem::size_of::<f64>()
+        }
+    };
+    let float = match param.precision {
+        Precision::Single => std::mem::size_of::<f32>(),
+        Precision::Double => std::mem::size_of::<f64>(),
+    };
+    width * height * (2 * fields * float + masks + part)
+}
+
+/// Layers are generated in pairs, and only as many pairs are kept in flight
+/// as the memory budget allows.  `reserved` is the memory used by the rest
+/// of the render.
+pub fn squiggles_layers<T: FftFloat>(
     n: usize,
-    plan: &Plan2D,
+    plan: &Plan2D<T>,
     param: &SquigglesParam,
-) -> impl Iterator<Item = Array2<u8>> {
+    reserved: usize,
+) -> impl Iterator<Item = Array2<u16>> {
     let pl = plan.clone();
     let pa = param.clone();
-    make_layers_n((n + 1) / 2, move |i| {
+    let bytes = squiggles_pair_bytes(plan.width(), plan.height(), param);
+    make_layers_n_sized(n.div_ceil(2), bytes, reserved, move |i| {
         generate_squiggles(&pl, &pa, 2 * i == n - 1)
     })
     .flat_map(|l| l.into_iter())
 }
 
-pub fn squiggles_layers_symmetric(
+pub fn squiggles_layers_symmetric<T: FftFloat>(
     n: usize,
     sym: SymmetryGroup,
-    plan: &Plan2D,
+    plan: &Plan2D<T>,
     param: &SquigglesParam,
-) -> impl Iterator<Item = SymmetricCanvas<u8>> {
+    reserved: usize,
+) -> impl Iterator<Item = SymmetricCanvas<u16>> {
     let pl = plan.clone();
     let pa = param.clone();
-    make_layers_n((n + 1) / 2, move |i| {
+    let bytes = squiggles_pair_bytes(plan.width(), plan.height(), param);
+    make_layers_n_sized(n.div_ceil(2), bytes, reserved, move |i| {
         generate_squiggles_symmetric(sym, &pl, &pa, 2 * i == n - 1)
     })
     .flat_map(|l| l.into_iter())
 }
 
+/// The bottom layer of the regions style, which covers the whole image.
+fn base_mask(style: &SquiggleStyle, size: u32, sym: SymmetryGroup) -> Option<SymmetricCanvas<u16>> {
+    match style {
+        SquiggleStyle::Regions { .. } => {
+            let full = Array2::from_elem((size as usize, size as usize), u16::MAX);
+            Some(SymmetricCanvas::from_wrap_canvas(full.into(), sym))
+        }
+        _ => None,
+    }
+}
+
 #[derive(Deserialize)]
 pub struct Squiggles {
     pub symmetry: SymmetryChoice,
     pub size: u32,
+    /// The number of parts of fields, each of which gives one layer, or one
+    /// for each level of contours with separate colors.
     pub colors: usize,
+    #[serde(default)]
+    pub style: SquiggleStyle,
+    #[serde(default)]
+    pub filter: SpectralFilter,
+    /// The exponent of the power law filters.
     pub exponent: f64,
     pub alpha: f64,
+    /// Fields at doubling frequencies that are summed into each field.
+    #[serde(default)]
+    pub octaves: Octaves,
     pub thickness: f64,
     pub sharpness: f64,
+    #[serde(default)]
+    pub background: Background,
+    #[serde(default)]
+    pub shading: Shading,
+    #[serde(default)]
+    pub palette: Palette,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub quality: Quality,
+    #[serde(default)]
+    pub precision: Precision,
 }
 
-impl symart_base::Design for Squiggles {
+impl Squiggles {
+    /// The factor by which a preview is shrunk: the largest divisor of the
+    /// size that leaves an even size at or above the preview size.  The
+    /// noise of the preview is then the full size noise summed over blocks.
+    /// If the size has no such divisor, the preview is larger than the
+    /// preview size, up to the full size.
+    fn reduction(&self) -> usize {
+        let size = self.size as usize;
+        let target = self.quality.scale(self.size, self.size) * (size as f64);
+        let most = ((size as f64) / target).floor().max(1.) as usize;
+        (1..=most)
+            .rev()
+            .find(|&r| size.is_multiple_of(r) && (size / r).is_multiple_of(2))
+            .unwrap_or(1)
+    }
+
+    /// The size of the output: the size divided by the reduction.
+    fn output_size(&self) -> u32 {
+        self.size / (self.reduction() as u32)
+    }
+
+    fn image_bytes(&self) -> usize {
+        let alpha = self.background == Background::Transparent;
+        memory::image_bytes(self.output_size(), self.output_size(), alpha)
+    }
+
+    fn param(&self) -> SquigglesParam {
+        SquigglesParam {
+            style: self.style.clone(),
+            filter: self.filter.clone(),
+            exponent: self.exponent,
+            alpha: self.alpha,
+            octaves: self.octaves.clone(),
+            thickness: self.thickness,
+            sharpness: self.sharpness,
+            precision: self.precision,
+            reduction: self.reduction(),
+        }
+    }
+
+    fn plan<T: FftFloat>(&self) -> Plan2D<T> {
+        let size = self.output_size() as usize;
+        Plan2D::cached(size, size)
+    }
+
+    fn num_layers(&self) -> usize {
+        let base = matches!(self.style, SquiggleStyle::Regions { .. }) as usize;
+        base + self.colors * self.style.layers_per_part()
+    }
+
+    fn layer_masks(&self, sym: SymmetryGroup) -> impl Iterator<Item = SymmetricCanvas<u16>> {
+        let (n, param, reserved) = (self.colors, self.param(), self.image_bytes());
+        let base = base_mask(&self.style, self.output_size(), sym);
+        let layers: Box<dyn Iterator<Item = _>> = match self.precision {
+            Precision::Single => {
+                let plan = self.plan::<f32>();
+                Box::new(squiggles_layers_symmetric(n, sym, &plan, &param, reserved))
+            }
+            Precision::Double => {
+                let plan = self.plan::<f64>();
+                Box::new(squiggles_layers_symmetric(n, sym, &plan, &param, reserved))
+            }
+        };
+        base.into_iter().chain(layers)
+    }
+
+    /// The symmetry, the seed that the noise of each pair of layers is
+    /// derived from, as in `make_layers_n_sized`, and the paints.
+    fn seed_and_paints(&self) -> (SymmetryGroup, u64, Vec<Paint>) {
+        with_optional_seed(self.seed, || {
+            let sym = self.symmetry.into();
+            let seed = next_seed();
+            let paints = (0..self.num_layers())
+                .map(|_| Paint::random(self.shading, &self.palette))
+                .collect();
+            (sym, seed, paints)
+        })
+    }
+}
+
+impl Design for Squiggles {
     fn name() -> &'static str {
         "Squiggles"
     }
@@ -201,6 +687,8 @@ impl symart_base::Design for Squiggles {
                 "symmetry": schema::symmetries(),
                 "size": schema::size_even(),
                 "colors": schema::num_colors(),
+                "style": style_schema(),
+                "filter": schema::spectral_filter(),
                 "exponent": {
                     "type": "number",
                     "title": "Exponent",
@@ -213,6 +701,7 @@ impl symart_base::Design for Squiggles {
                     "maximum": 2,
                     "default": 2
                 },
+                "octaves": schema::octaves(),
                 "thickness": {
                     "type": "number",
                     "title": "Thickness",
@@ -222,33 +711,379 @@ impl symart_base::Design for Squiggles {
                     "type": "number",
                     "title": "Sharpness",
                     "default": 2
-                }
+                },
+                "background": schema::background(),
+                "shading": schema::shading(),
+                "palette": schema::palette(),
+                "seed": schema::seed(),
+                "quality": schema::quality(),
+                "precision": schema::precision()
             },
             "required": ["symmetry", "size", "colors", "alpha", "thickness", "sharpness"]
         })
     }
 
     fn draw(&self) -> Result<DrawResponse, Box<dyn std::error::Error>> {
-        let sym: SymmetryGroup = self.symmetry.into();
-        let mut im = RgbImage::new(self.size, self.size);
-        let param = SquigglesParam {
-            exponent: self.exponent,
-            alpha: self.alpha,
-            thickness: self.thickness,
-            sharpness: self.sharpness,
+        self.filter.validate()?;
+        with_optional_seed(self.seed, || {
+            let sym: SymmetryGroup = self.symmetry.into();
+            let size = self.output_size();
+            let mut im = symart_base::layer::background_image(size, size, self.background);
+            self.layer_masks(sym).for_each(|layer| {
+                let paint = Paint::random(self.shading, &self.palette);
+                symart_base::layer::merge_layer(&mut im, layer.as_ref(), &paint);
+            });
+            Ok(DrawResponse {
+                im,
+                sym: sym.into(),
+            })
+        })
+    }
+
+    fn memory_estimate(&self) -> usize {
+        let size = self.output_size() as usize;
+        let pair_bytes = squiggles_pair_bytes(size, size, &self.param());
+        memory::layered_estimate(self.colors.div_ceil(2), pair_bytes, self.image_bytes())
+    }
+}
+
+impl LayeredDesign for Squiggles {
+    fn draw_layers(&self) -> Result<LayeredImage, Box<dyn std::error::Error>> {
+        self.filter.validate()?;
+        with_optional_seed(self.seed, || {
+            let sym: SymmetryGroup = self.symmetry.into();
+            let layers = self
+                .layer_masks(sym)
+                .map(|mask| {
+                    let paint = Paint::random(self.shading, &self.palette);
+                    Layer::new(mask.into(), paint, Self::name())
+                })
+                .collect();
+            let size = self.output_size();
+            Ok(LayeredImage {
+                width: size,
+                height: size,
+                background: self.background,
+                layers,
+                sym: sym.into(),
+            })
+        })
+    }
+
+    fn layers_memory_estimate(&self) -> usize {
+        let size = self.output_size() as usize;
+        let pair_bytes = squiggles_pair_bytes(size, size, &self.param());
+        let kept = self.num_layers() * size * size * std::mem::size_of::<u16>();
+        let n = self.colors.div_ceil(2);
+        memory::kept_layers_estimate(n, pair_bytes, self.image_bytes(), kept)
+    }
+}
+
+impl Animate for Squiggles {
+    /// The phase of each filtered field turns once around the circle, so
+    /// each pair of layers trades places and the animation loops.  The
+    /// fields of all pairs are kept for the whole animation.  A layer
+    /// without a partner comes from a real field, as in `draw`, and its
+    /// masks are drawn once, so it stays still.
+    fn animation(&self) -> Result<Animation, Box<dyn std::error::Error>> {
+        self.filter.validate()?;
+        match self.precision {
+            Precision::Single => self.animation_with::<f32>(),
+            Precision::Double => self.animation_with::<f64>(),
+        }
+    }
+}
+
+/// What an animation keeps of a pair of layers: the field whose phase
+/// turns, or the masks of a layer without a partner.
+enum PairField<T> {
+    Turning(Array2<Complex<T>>),
+    Still(Vec<SymmetricCanvas<u16>>),
+}
+
+impl Squiggles {
+    fn animation_with<T: FftFloat>(&self) -> Result<Animation, Box<dyn std::error::Error>> {
+        with_optional_seed(self.seed, || {
+            let sym: SymmetryGroup = self.symmetry.into();
+            let (plan, param) = (self.plan::<T>(), self.param());
+            let (n, size) = (self.colors, self.output_size());
+            let bytes = squiggles_pair_bytes(size as usize, size as usize, &param);
+            let fields: Vec<PairField<T>> = {
+                let param = param.clone();
+                make_layers_n_sized(n.div_ceil(2), bytes, self.image_bytes(), move |i| {
+                    if 2 * i == n - 1 {
+                        PairField::Still(generate_squiggles_symmetric(sym, &plan, &param, true))
+                    } else {
+                        PairField::Turning(generate_field_symmetric(sym, &plan, &param, false))
+                    }
+                })
+                .collect()
+            };
+            let paints: Vec<Paint> = (0..self.num_layers())
+                .map(|_| Paint::random(self.shading, &self.palette))
+                .collect();
+            let base = base_mask(&param.style, size, sym);
+            let background = self.background;
+            let frame = move |t: f64| {
+                let rot = Complex::from_polar(T::one(), T::from_f64(2. * PI * t).unwrap());
+                let mut im = symart_base::layer::background_image(size, size, background);
+                let mut paints = paints.iter();
+                let mut merge = |mask: &SymmetricCanvas<u16>| {
+                    if let Some(paint) = paints.next() {
+                        symart_base::layer::merge_layer(&mut im, mask.as_ref(), paint);
+                    }
+                };
+                base.iter().for_each(&mut merge);
+                for field in &fields {
+                    match field {
+                        PairField::Turning(arr) => {
+                            let turned = arr.mapv(|c| c * rot);
+                            field_squiggles_symmetric(sym, &turned, &param, false)
+                                .iter()
+                                .for_each(&mut merge);
+                        }
+                        PairField::Still(masks) => masks.iter().for_each(&mut merge),
+                    }
+                }
+                DrawResponse {
+                    im,
+                    sym: sym.into(),
+                }
+            };
+            Ok(Animation {
+                frame: Box::new(frame),
+                periodic: true,
+            })
+        })
+    }
+}
+
+impl Morph for Squiggles {
+    /// Both designs must have the same symmetry, size, number of colors, of
+    /// layers and of octaves, and quality.  The noise of the two is blended
+    /// before it is filtered, with weights `cos^(2/alpha)` and
+    /// `sin^(2/alpha)` of an angle that turns a quarter circle, where
+    /// `alpha` is the stability index of each design.  This keeps the
+    /// distribution of the noise when the indices are equal, and is the
+    /// great circle blend for Gaussian noise.  The other parameters are
+    /// blended linearly, except for the stability index, which only affects
+    /// the noise.
+    fn morph(&self, other: &Self) -> Result<Animation, Box<dyn std::error::Error>> {
+        self.filter.validate()?;
+        other.filter.validate()?;
+        match self.precision {
+            Precision::Single => self.morph_with::<f32>(other),
+            Precision::Double => self.morph_with::<f64>(other),
+        }
+    }
+}
+
+impl Squiggles {
+    /// Morphs in the precision `T`, which is used for both designs.
+    fn morph_with<T: FftFloat>(
+        &self,
+        other: &Self,
+    ) -> Result<Animation, Box<dyn std::error::Error>> {
+        if self.size != other.size {
+            return Err(Box::new(MorphError::Mismatch("size")));
+        }
+        if self.colors != other.colors {
+            return Err(Box::new(MorphError::Mismatch("number of colors")));
+        }
+        if self.num_layers() != other.num_layers() {
+            return Err(Box::new(MorphError::Mismatch("number of layers")));
+        }
+        if self.quality != other.quality {
+            return Err(Box::new(MorphError::Mismatch("quality")));
+        }
+        if self.octaves.num_octaves() != other.octaves.num_octaves() {
+            return Err(Box::new(MorphError::Mismatch("number of octaves")));
+        }
+        let (sym, seed_a, paints_a) = self.seed_and_paints();
+        let (sym_b, seed_b, paints_b) = other.seed_and_paints();
+        if sym != sym_b {
+            return Err(Box::new(MorphError::Mismatch("symmetry")));
+        }
+        let plan = self.plan::<T>();
+        let (param_a, param_b) = (self.param(), other.param());
+        let (background_a, background_b) = (self.background, other.background);
+        let (n, size) = (self.colors, self.output_size());
+        let frame = move |t: f64| {
+            let (s, c) = (0.5 * PI * t).sin_cos();
+            let c = T::from_f64(c.powf(2. / param_a.alpha)).unwrap();
+            let s = T::from_f64(s.powf(2. / param_b.alpha)).unwrap();
+            let param = SquigglesParam {
+                style: param_a.style.mix(&param_b.style, t),
+                filter: param_a.filter.mix(&param_b.filter, t),
+                exponent: mix(param_a.exponent, param_b.exponent, t),
+                alpha: param_a.alpha,
+                octaves: param_a.octaves.mix(&param_b.octaves, t),
+                thickness: mix(param_a.thickness, param_b.thickness, t),
+                sharpness: mix(param_a.sharpness, param_b.sharpness, t),
+                precision: param_a.precision,
+                reduction: param_a.reduction,
+            };
+            let background = background_a.mix(background_b, t);
+            let mut im = symart_base::layer::background_image(size, size, background);
+            // The noise of each octave is drawn again for every frame, so
+            // that only one octave of each design is held at a time.
+            let layers = (0..n.div_ceil(2)).flat_map(|i| {
+                let single = 2 * i == n - 1;
+                let octaves = |p: &SquigglesParam| p.octaves.octaves(&p.field(Some(sym), single));
+                let (octaves_a, octaves_b) = (octaves(&param_a), octaves(&param_b));
+                let mut rng_a = seeded_rng(derive_seed(seed_a, i as u64));
+                let mut rng_b = seeded_rng(derive_seed(seed_b, i as u64));
+                let octaves = param.octaves.octaves(&param.field(Some(sym), single));
+                let fields = octaves_a.iter().zip(&octaves_b).zip(&octaves);
+                let arr = octave_sum(fields.map(|(((fa, _), (fb, _)), (f, w))| {
+                    let a = white_noise(&plan, &mut rng_a, fa);
+                    let b = white_noise(&plan, &mut rng_b, fb);
+                    let mut arr = Zip::from(&a).and(&b).map_collect(|&a, &b| a * c + b * s);
+                    apply_filter(&plan, &mut arr, f);
+                    (arr, *w)
+                }));
+                field_squiggles_symmetric(sym, &arr, &param, single)
+            });
+            let masks = base_mask(&param.style, size, sym).into_iter().chain(layers);
+            for (mask, (pa, pb)) in masks.zip(paints_a.iter().zip(&paints_b)) {
+                symart_base::layer::merge_layer(&mut im, mask.as_ref(), &pa.mix(pb, t));
+            }
+            DrawResponse {
+                im,
+                sym: sym.into(),
+            }
         };
-        let plan = Plan2D::new(
-            &mut FftPlanner::new(),
-            self.size as usize,
-            self.size as usize,
-        );
-        squiggles_layers_symmetric(self.colors, sym, &plan, &param).for_each(|layer| {
-            let col = symart_base::rng::sample(symart_base::random::Color);
-            symart_base::layer::merge_one(&mut im, layer.as_ref(), image::Rgb(col));
-        });
-        Ok(DrawResponse {
-            im,
-            sym: sym.into(),
+        Ok(Animation {
+            frame: Box::new(frame),
+            periodic: false,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symart_base::canvas::Coord;
+    use symart_base::field::white_noise_real;
+    use symart_base::rng::{sample_fn, with_seed};
+    use symart_base::symmetry::transformations;
+
+    #[test]
+    fn preview_noise_is_summed_full_noise() {
+        // 1100 / 4 is odd, so the preview has to be 550 wide.
+        let squiggles: Squiggles = serde_json::from_value(serde_json::json!({
+            "symmetry": "P1",
+            "size": 1100,
+            "colors": 1,
+            "exponent": 2,
+            "alpha": 2,
+            "thickness": 1,
+            "sharpness": 2,
+            "quality": "Preview"
+        }))
+        .unwrap();
+        let (r, size) = (squiggles.reduction(), squiggles.output_size() as usize);
+        assert_eq!(r * size, 1100);
+        let params = squiggles.param().field(None, true);
+        let full_params = FieldParams {
+            reduction: 1,
+            ..params.clone()
+        };
+        let preview = Plan2D::<f64>::cached(size, size);
+        let full = Plan2D::<f64>::cached(r * size, r * size);
+        let small = with_seed(3, || {
+            sample_fn(|rng| white_noise_real(&preview, rng, &params))
+        });
+        let large = with_seed(3, || {
+            sample_fn(|rng| white_noise_real(&full, rng, &full_params))
+        });
+        for ((x, y), &v) in small.indexed_iter() {
+            let block = large.slice(ndarray::s![x * r..(x + 1) * r, y * r..(y + 1) * r]);
+            assert!((block.sum() - v).abs() < 1e-9);
+        }
+    }
+
+    /// Checks that the masks drawn in `style` are invariant under every
+    /// group with a hexagonal lattice.
+    fn assert_symmetric_on_hexagonal_grids(style: serde_json::Value) {
+        let n = 64;
+        let plan = Plan2D::<f64>::cached(n, n);
+        for sym in [
+            SymmetryGroup::P3,
+            SymmetryGroup::P31M,
+            SymmetryGroup::P3M1,
+            SymmetryGroup::P6,
+            SymmetryGroup::P6M,
+        ] {
+            let squiggles: Squiggles = serde_json::from_value(serde_json::json!({
+                "symmetry": sym,
+                "size": n,
+                "colors": 2,
+                "style": style,
+                "exponent": 2,
+                "alpha": 2,
+                "thickness": 1,
+                "sharpness": 2
+            }))
+            .unwrap();
+            let masks = with_seed(5, || {
+                generate_squiggles_symmetric(sym, &plan, &squiggles.param(), false)
+            });
+            for mask in &masks {
+                for t in transformations(sym, (n / 2) as i32) {
+                    for x in 0..n as i32 {
+                        for y in 0..n as i32 {
+                            let pt = Coord::new(x, y);
+                            let (a, b) = (mask[pt], mask[t.apply(&pt)]);
+                            assert!(a.abs_diff(b) <= 1, "{sym} {style}: {a} {b}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn contours_are_symmetric_on_hexagonal_grids() {
+        assert_symmetric_on_hexagonal_grids(serde_json::json!({
+            "type": "Contours",
+            "levels": 5,
+            "spacing": 0.5,
+            "width": 2,
+            "separate_colors": false
+        }));
+    }
+
+    #[test]
+    fn regions_are_symmetric_on_hexagonal_grids() {
+        for softness in [0., 0.3] {
+            assert_symmetric_on_hexagonal_grids(serde_json::json!({
+                "type": "Regions",
+                "bands": 4,
+                "equal_areas": false,
+                "spacing": 0.7,
+                "softness": softness
+            }));
+        }
+    }
+
+    #[test]
+    fn single_layer_stays_still() {
+        let squiggles: Squiggles = serde_json::from_value(serde_json::json!({
+            "symmetry": "P4",
+            "size": 64,
+            "colors": 1,
+            "exponent": 2,
+            "alpha": 2,
+            "thickness": 1,
+            "sharpness": 2,
+            "seed": 1
+        }))
+        .unwrap();
+        let animation = squiggles.animation().unwrap();
+        let still = (animation.frame)(0.).im;
+        for t in [0.25, 0.5, 0.75] {
+            assert_eq!((animation.frame)(t).im, still);
+        }
+    }
+}